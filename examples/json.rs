@@ -8,7 +8,7 @@ fn main() {
     use std::io;
     use tenjin::*;
 
-    let mut tenjin = Tenjin::empty();
+    let tenjin = Tenjin::empty();
 
     tenjin.register("test", Template::compile("
         First Name: {{ person.name.first }}
@@ -35,9 +35,9 @@ fn main() {
     });
 
     let output   = io::stdout();
-    let template = tenjin.get("test").unwrap();
+    let template = tenjin.get("test").unwrap().unwrap();
 
-    tenjin.render(template, &data, &mut output.lock()).unwrap();
+    tenjin.render(&template, &data, &mut output.lock()).unwrap();
 }
 
 #[cfg(not(feature = "serde_json"))]
@@ -33,7 +33,7 @@ context! {
 }
 
 fn main() {
-    let mut tenjin = Tenjin::empty();
+    let tenjin = Tenjin::empty();
 
     tenjin.register("test", Template::compile("
         { header }
@@ -74,7 +74,7 @@ fn main() {
     };
 
     let output   = io::stdout();
-    let template = tenjin.get("test").unwrap();
+    let template = tenjin.get("test").unwrap().unwrap();
 
-    tenjin.render(template, &data, &mut output.lock()).unwrap();
+    tenjin.render(&template, &data, &mut output.lock()).unwrap();
 }
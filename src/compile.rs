@@ -1,24 +1,35 @@
+use context::Value;
+use escape::{Escaper, Position, Tracker};
 use path::PathBuf;
-use std::borrow::Borrow;
 use std::error::Error as StdError;
+use std::path::{Path as FilePath, PathBuf as FilePathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
 use std::{fmt, mem};
 
 #[derive(Debug, Clone)]
 pub struct Template {
     body: Vec<Statement>,
+    // Where each statement in the body starts.
+    spans: Vec<Span>,
+    name: Option<String>,
+    escaper: Option<Arc<dyn Escaper>>,
+    // The file it was read from, and when that was last modified.
+    file: Option<(FilePathBuf, Option<SystemTime>)>,
 }
 
 #[derive(Debug, Clone)]
 pub enum Statement {
     Cond {
-        pred: PathBuf,
-        then: Template,
+        branches: Vec<(Expr, Template)>,
         otherwise: Option<Template>,
     },
     For {
         ident: String,
+        key:   Option<String>,
         path:  PathBuf,
         body:  Template,
+        otherwise: Option<Template>,
     },
     Include {
         template: String,
@@ -26,166 +37,444 @@ pub enum Statement {
     },
     Inject {
         path: PathBuf,
+        fallbacks: Vec<Expr>,
+        filters: Vec<(String, Vec<Value<'static>>)>,
+        position: Position,
     },
     Content {
         content: String,
     },
+    Extends {
+        template: String,
+    },
+    Block {
+        name: String,
+        body: Template,
+    },
+    Super,
+}
+
+/// A predicate, as found in `if` statements.
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Path(PathBuf),
+    Literal(Value<'static>),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(Op, Box<Expr>, Box<Expr>),
+}
+
+/// A comparison operator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
 }
 
 // Rules
 // =====
 //
-// stmt  := for | cond | incl | var
-// cond  := 'if' path '}' block [ '{' else '}' block ] '{' end
-// for   := 'for' ident 'in' path '}' block '{' 'end'
+// stmt  := for | cond | incl | ext | blk | sup | verb | var
+// cond  := 'if' expr '}' block { '{' elif '}' block } [ '{' else '}' block ] '{' end
+// elif  := 'elif' expr | 'else' 'if' expr
+// for   := 'for' [ ident ',' ] ident 'in' path '}' block [ '{' else '}' block ] '{' 'end'
 // incl  := 'include' path [ 'with' path ]
-// var   := path \ 'for' | 'include' | 'if'
+// ext   := 'extends' path
+// blk   := 'block' ident '}' block '{' 'end'
+// sup   := 'super'
+// verb  := 'verbatim' '}' { char } '{' 'endverbatim'
+// var   := path { alt } { filt } \ 'for' | 'include' | 'if' | 'extends' | 'block' | 'super' | 'verbatim'
+// alt   := ( 'or' | '?' ) opnd
+// filt  := '|' ident { lit }
+//
+// expr  := conj { 'or' conj }
+// conj  := neg { 'and' neg }
+// neg   := 'not' neg | atom
+// atom  := '(' expr ')' | opnd [ op opnd ]
+// opnd  := lit | path \ 'and' | 'or'
+// op    := '==' | '!=' | '<' | '<=' | '>' | '>='
+//
+// lit   := str | num | 'true' | 'false'
+// str   := '"' { char } '"'
+// num   := [ '-' ] digit { char }
 //
 // ident := word \ { char } '.' { char }
 // path  := word
+// word  := { char } \ { char } (' ' | '{' | '}' | '(' | ')' | ',' | '|' | '?' | '"' | op) { char }
 //
 // text  := { char }
-// word  := { char } \ { char } (' ' | '{' | '}') { char }
+//
+// Comments are written as '{#' { char } '#}', and are skipped entirely. One
+// that's never closed is an error.
+//
+// Tags may be opened with '{-' or closed with '-}' instead, to strip the
+// whitespace from the end of the text before or the start of the text after.
 
 impl Template {
+    fn new(body: Vec<Statement>, spans: Vec<Span>) -> Template {
+        Template {
+            body,
+            spans,
+            name: None,
+            escaper: None,
+            file: None,
+        }
+    }
+
     pub fn compile(src: &str) -> Result<Template, Error> {
-        let mut body = Vec::new();
-        let mut lex  = Lexer::new(src);
-    
-        while let Some(sym) = lex.next() {
-            match sym {
-                Symbol::Text(s) => {
-                    body.push(Statement::Content {
-                        content: s.into(),
-                    });
-                }
-                Symbol::Open => {
-                    body.push(stmt(&mut lex)?);
-                    expect(&mut lex, Symbol::Close, "'}'")?;
-                }
-                x => {
-                    return unexpected("text or '{'", Some(x));
+        Template::compile_all(src).map_err(|mut errors| errors.remove(0))
+    }
+
+    /// Like `compile`, but recovers from errors and keeps going, so that
+    /// every problem in the template is reported at once.
+    pub fn compile_all(src: &str) -> Result<Template, Vec<Error>> {
+        let mut lex    = Lexer::new(src);
+        let mut errors = Vec::new();
+
+        let (body, _) = block(&mut lex, &mut errors, &[]);
+
+        if errors.is_empty() {
+            Ok(body)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// The escaper for variables in this template, if it doesn't use the
+    /// one its `Tenjin` is set up with.
+    pub fn escaper(&self) -> Option<&dyn Escaper> {
+        self.escaper.as_deref()
+    }
+
+    pub fn set_escaper(&mut self, escaper: Option<Arc<dyn Escaper>>) {
+        self.escaper = escaper;
+    }
+
+    /// The file the template was read from, if any.
+    pub fn file(&self) -> Option<&FilePath> {
+        self.file.as_ref().map(|x| &*x.0)
+    }
+
+    pub(crate) fn set_file(&mut self, path: FilePathBuf, modified: Option<SystemTime>) {
+        self.file = Some((path, modified));
+    }
+
+    /// When the file the template was read from was last modified, as of
+    /// reading it, if that's known.
+    pub(crate) fn modified(&self) -> Option<SystemTime> {
+        self.file.as_ref().and_then(|x| x.1)
+    }
+
+    /// The name the template was registered under, if any.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Names the template, for the sake of error messages.
+    pub fn set_name<S: Into<String>>(&mut self, name: S) {
+        fn visit(template: &mut Template, name: &str) {
+            template.name = Some(name.into());
+
+            for stmt in &mut template.body {
+                match *stmt {
+                    Statement::Block { ref mut body, .. } => visit(body, name),
+                    Statement::For { ref mut body, ref mut otherwise, .. } => {
+                        visit(body, name);
+                        otherwise.iter_mut().for_each(|x| visit(x, name));
+                    }
+                    Statement::Cond { ref mut branches, ref mut otherwise } => {
+                        branches.iter_mut().for_each(|(_, then)| visit(then, name));
+                        otherwise.iter_mut().for_each(|x| visit(x, name));
+                    }
+                    _ => {}
                 }
             }
         }
 
-        Ok(Template { body })
+        visit(self, &name.into());
     }
 
     pub fn body(&self) -> &[Statement] {
         &*self.body
     }
-}
 
-fn stmt(lex: &mut Lexer) -> Result<Statement, Error> {
-    match lex.peek() {
-        Some(&Symbol::Word("if")) => cond(lex),
-        Some(&Symbol::Word("for")) => forr(lex),
-        Some(&Symbol::Word("include")) => incl(lex),
-        Some(&Symbol::Word(_)) => var(lex),
-        _ => unexpected("'if', 'for', 'include' or path", lex.peek()),
+    /// Where each statement in the body starts.
+    pub fn spans(&self) -> &[Span] {
+        &self.spans
     }
-}
 
-fn cond(lex: &mut Lexer) -> Result<Statement, Error> {
-    expect(lex, Symbol::Word("if"), "'if'")?;
+    /// The name of the template this one extends, if any.
+    pub fn parent(&self) -> Option<&str> {
+        self.body.iter().filter_map(|stmt| match *stmt {
+            Statement::Extends { ref template } => Some(&**template),
+            _ => None,
+        }).next()
+    }
 
-    let pred = path(lex)?.into();
+    /// Finds the definition of the named block, searching nested blocks too.
+    pub fn block(&self, name: &str) -> Option<&Template> {
+        for stmt in &self.body {
+            let found = match *stmt {
+                Statement::Block { name: ref x, ref body } if x == name => {
+                    return Some(body);
+                }
+                Statement::Block { ref body, .. } => body.block(name),
+                Statement::For { ref body, ref otherwise, .. } => {
+                    body.block(name).or_else(|| {
+                        otherwise.as_ref().and_then(|x| x.block(name))
+                    })
+                }
+                Statement::Cond { ref branches, ref otherwise } => {
+                    branches.iter()
+                        .map(|(_, then)| then)
+                        .chain(otherwise)
+                        .filter_map(|x| x.block(name))
+                        .next()
+                }
+                _ => None,
+            };
 
-    expect(lex, Symbol::Close, "'}'")?;
+            if found.is_some() {
+                return found;
+            }
+        }
 
-    let mut then = Vec::new();
-    let mut otherwise = None;
+        None
+    }
+
+    /// The names of the templates this one includes, searching nested
+    /// blocks too.
+    pub fn includes(&self) -> Vec<&str> {
+        fn visit<'a>(template: &'a Template, names: &mut Vec<&'a str>) {
+            for stmt in &template.body {
+                match *stmt {
+                    Statement::Include { ref template, .. } => names.push(template),
+                    Statement::Block { ref body, .. } => visit(body, names),
+                    Statement::For { ref body, ref otherwise, .. } => {
+                        visit(body, names);
+                        otherwise.iter().for_each(|x| visit(x, names));
+                    }
+                    Statement::Cond { ref branches, ref otherwise } => {
+                        branches.iter().for_each(|(_, then)| visit(then, names));
+                        otherwise.iter().for_each(|x| visit(x, names));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut names = Vec::new();
+        visit(self, &mut names);
+        names
+    }
+}
+
+/// Parses statements until one of the words in `ends` opens a tag, and
+/// returns that word. The rest of the terminating tag is left unconsumed.
+fn block<'a>(
+    lex: &mut Lexer<'a>,
+    errors: &mut Vec<Error>,
+    ends: &[&'static str],
+) -> (Template, Option<&'a str>) {
+    let mut body  = Vec::new();
+    let mut spans = Vec::new();
 
     loop {
         match lex.next() {
             Some(Symbol::Text(s)) => {
-                let mut body = match otherwise {
-                    Some(ref mut body) => body,
-                    None => &mut then,
-                };
-
+                lex.html.text(s);
+                spans.push(lex.span());
                 body.push(Statement::Content {
                     content: s.into(),
                 });
             }
             Some(Symbol::Open) => {
-                match lex.peek() {
-                    Some(&Symbol::Word("end")) => {
+                if let Some(&Symbol::Word(word)) = lex.peek() {
+                    if ends.contains(&word) {
                         let _ = lex.next();
-                        break;
-                    }
-                    Some(&Symbol::Word("else")) => {
-                        let _ = lex.next();
-                        expect(lex, Symbol::Close, "'}'")?;
-                        otherwise = Some(Vec::new());
-                    }
-                    _ => {
-                        let mut body = match otherwise {
-                            Some(ref mut body) => body,
-                            None => &mut then,
-                        };
-
-                        body.push(stmt(lex)?);
-                        expect(lex, Symbol::Close, "'}'")?;
+                        return (Template::new(body, spans), Some(word));
                     }
                 }
+
+                let span = lex.span();
+                let res = stmt(lex, errors).and_then(|stmt| {
+                    spans.push(span);
+                    body.push(stmt);
+                    expect(lex, Symbol::Close, "'}'")
+                });
+
+                if let Err(e) = res {
+                    record(lex, errors, e);
+                    recover(lex);
+                }
+            }
+            Some(Symbol::Comment) => {
+                let mut span = lex.span();
+                span.end = span.start + 2;
+
+                record(lex, errors, Error {
+                    kind: ErrorKind::UnterminatedComment,
+                    span,
+                    line: lex.line_of(span).into(),
+                    file: None,
+                });
+            }
+            None if ends.is_empty() => {
+                return (Template::new(body, spans), None);
             }
             x => {
-                return unexpected("text or '{'", x);
+                let eof = x.is_none();
+
+                record(lex, errors, error(lex, "text or '{'", x));
+
+                if eof {
+                    return (Template::new(body, spans), None);
+                }
             }
         }
     }
+}
 
-    let then = Template { body: then };
-    let otherwise = otherwise.map(|x| {
-        Template { body: x }
-    });
-
-    Ok(Statement::Cond { pred, then, otherwise })
+/// Records an error, unless the end of the template was already reported,
+/// since everything after that is just the same problem again.
+fn record(lex: &Lexer, errors: &mut Vec<Error>, error: Error) {
+    match errors.last() {
+        Some(last) if last.span.start == lex.full.len() => {}
+        Some(&Error { kind: ErrorKind::UnterminatedComment, .. }) => {}
+        _ => errors.push(error),
+    }
 }
 
-fn forr(lex: &mut Lexer) -> Result<Statement, Error> {
-    expect(lex, Symbol::Word("for"), "'for'")?;
+/// Skips the rest of a malformed tag, stopping after its closing brace or
+/// before the next opening one.
+fn recover(lex: &mut Lexer) {
+    while let Some(&Symbol::Word(_))
+        | Some(&Symbol::Punct(_))
+        | Some(&Symbol::Str(_)) = lex.peek()
+    {
+        let _ = lex.next();
+    }
 
-    let x = ident(lex)?.into();
+    if lex.peek() == Some(&Symbol::Close) {
+        let _ = lex.next();
+    }
+}
 
-    expect(lex, Symbol::Word("in"), "'in'")?;
+/// Parses the rest of a block statement's opening tag. If that fails, the
+/// error is recorded and the tag skipped, so that the body is still parsed
+/// and its `{ end }` is not mistaken for part of the enclosing block.
+fn header<'a, T, F>(lex: &mut Lexer<'a>, errors: &mut Vec<Error>, f: F) -> Option<T>
+where
+    F: FnOnce(&mut Lexer<'a>) -> Result<T, Error>,
+{
+    match f(lex) {
+        Ok(x) => Some(x),
+        Err(e) => {
+            record(lex, errors, e);
+            recover(lex);
+            None
+        }
+    }
+}
 
-    let y = path(lex)?.into();
+fn stmt(lex: &mut Lexer, errors: &mut Vec<Error>) -> Result<Statement, Error> {
+    match lex.peek() {
+        Some(&Symbol::Word("if")) => cond(lex, errors),
+        Some(&Symbol::Word("for")) => forr(lex, errors),
+        Some(&Symbol::Word("include")) => incl(lex),
+        Some(&Symbol::Word("extends")) => ext(lex),
+        Some(&Symbol::Word("block")) => blk(lex, errors),
+        Some(&Symbol::Word("super")) => sup(lex),
+        Some(&Symbol::Word("verbatim")) => verb(lex),
+        Some(&Symbol::Word(_)) => var(lex),
+        _ => {
+            let found = lex.next();
+            unexpected(lex, "keyword or path", found)
+        }
+    }
+}
 
-    expect(lex, Symbol::Close, "'}'")?;
+fn cond(lex: &mut Lexer, errors: &mut Vec<Error>) -> Result<Statement, Error> {
+    expect(lex, Symbol::Word("if"), "'if'")?;
 
-    let mut body = Vec::new();
+    let mut branches = Vec::new();
+    let mut end;
 
     loop {
-        match lex.next() {
-            Some(Symbol::Text(s)) => {
-                body.push(Statement::Content {
-                    content: s.into(),
-                });
-            }
-            Some(Symbol::Open) => {
-                match lex.peek() {
-                    Some(&Symbol::Word("end")) => {
-                        let _ = lex.next();
-                        break;
-                    }
-                    _ => {
-                        body.push(stmt(lex)?);
-                        expect(lex, Symbol::Close, "'}'")?;
-                    }
-                }
-            }
-            x => {
-                println!("{}", lex.src);
-                return unexpected("text or '{'", x);
-            }
+        let pred = header(lex, errors, |lex| {
+            let pred = expr(lex)?;
+            expect(lex, Symbol::Close, "'}'")?;
+            Ok(pred)
+        }).unwrap_or_else(|| Expr::Path(PathBuf::new()));
+
+        let (then, next) = block(lex, errors, &["elif", "else", "end"]);
+        branches.push((pred, then));
+        end = next;
+
+        // `else if` is the same as `elif`.
+        if end == Some("else") && lex.peek() == Some(&Symbol::Word("if")) {
+            let _ = lex.next();
+            end = Some("elif");
+        }
+
+        if end != Some("elif") {
+            break;
         }
     }
 
-    let body = Template { body };
-    Ok(Statement::For { ident: x, path: y, body })
+    let mut otherwise = None;
+
+    while let Some(word) = end.filter(|&x| x != "end") {
+        if otherwise.is_some() {
+            // Nothing may follow the else block.
+            record(lex, errors, error(lex, "'end'", Some(Symbol::Word(word))));
+            recover(lex);
+        } else {
+            header(lex, errors, |lex| expect(lex, Symbol::Close, "'}'"));
+        }
+
+        let (body, next) = block(lex, errors, &["elif", "else", "end"]);
+        otherwise = Some(body);
+        end = next;
+    }
+
+    Ok(Statement::Cond { branches, otherwise })
+}
+
+fn forr(lex: &mut Lexer, errors: &mut Vec<Error>) -> Result<Statement, Error> {
+    expect(lex, Symbol::Word("for"), "'for'")?;
+
+    let (k, x, y) = header(lex, errors, |lex| {
+        let mut k = None;
+        let mut x = ident(lex)?.into();
+
+        if lex.peek() == Some(&Symbol::Punct(",")) {
+            let _ = lex.next();
+            k = Some(x);
+            x = ident(lex)?.into();
+        }
+
+        expect(lex, Symbol::Word("in"), "'in'")?;
+        let y = path(lex)?.into();
+        expect(lex, Symbol::Close, "'}'")?;
+        Ok((k, x, y))
+    }).unwrap_or_default();
+
+    let (body, end) = block(lex, errors, &["else", "end"]);
+
+    let otherwise = if end == Some("else") {
+        header(lex, errors, |lex| expect(lex, Symbol::Close, "'}'"));
+        Some(block(lex, errors, &["end"]).0)
+    } else {
+        None
+    };
+
+    Ok(Statement::For { ident: x, key: k, path: y, body, otherwise })
 }
 
 fn incl(lex: &mut Lexer) -> Result<Statement, Error> {
@@ -203,9 +492,202 @@ fn incl(lex: &mut Lexer) -> Result<Statement, Error> {
     Ok(Statement::Include { template: x, context })
 }
 
+fn ext(lex: &mut Lexer) -> Result<Statement, Error> {
+    expect(lex, Symbol::Word("extends"), "'extends'")?;
+    let x = path(lex)?.into();
+    Ok(Statement::Extends { template: x })
+}
+
+fn blk(lex: &mut Lexer, errors: &mut Vec<Error>) -> Result<Statement, Error> {
+    expect(lex, Symbol::Word("block"), "'block'")?;
+
+    let name = header(lex, errors, |lex| {
+        let name = ident(lex)?.into();
+        expect(lex, Symbol::Close, "'}'")?;
+        Ok(name)
+    }).unwrap_or_default();
+
+    let (body, _) = block(lex, errors, &["end"]);
+
+    Ok(Statement::Block { name, body })
+}
+
+fn sup(lex: &mut Lexer) -> Result<Statement, Error> {
+    expect(lex, Symbol::Word("super"), "'super'")?;
+    Ok(Statement::Super)
+}
+
+fn verb(lex: &mut Lexer) -> Result<Statement, Error> {
+    expect(lex, Symbol::Word("verbatim"), "'verbatim'")?;
+    expect(lex, Symbol::Close, "'}'")?;
+
+    let content: String = match lex.raw("endverbatim") {
+        Some(content) => content.into(),
+        None => return unexpected(lex, "'{ endverbatim }'", None),
+    };
+
+    lex.html.text(&content);
+
+    expect(lex, Symbol::Open, "'{'")?;
+    expect(lex, Symbol::Word("endverbatim"), "'endverbatim'")?;
+
+    Ok(Statement::Content { content })
+}
+
 fn var(lex: &mut Lexer) -> Result<Statement, Error> {
     let x = path(lex)?.into();
-    Ok(Statement::Inject { path: x })
+    let mut fallbacks = Vec::new();
+    let mut filters   = Vec::new();
+
+    while let Some(&Symbol::Word("or")) | Some(&Symbol::Punct("?")) = lex.peek() {
+        let _ = lex.next();
+        fallbacks.push(opnd(lex)?);
+    }
+
+    while lex.peek() == Some(&Symbol::Punct("|")) {
+        let _ = lex.next();
+        let name = ident(lex)?.into();
+        let mut args = Vec::new();
+
+        while is_literal(lex.peek()) {
+            args.push(lit(lex)?);
+        }
+
+        filters.push((name, args));
+    }
+
+    let position = lex.html.position();
+    lex.html.inject();
+
+    Ok(Statement::Inject { path: x, fallbacks, filters, position })
+}
+
+fn expr(lex: &mut Lexer) -> Result<Expr, Error> {
+    let mut x = conj(lex)?;
+
+    while lex.peek() == Some(&Symbol::Word("or")) {
+        let _ = lex.next();
+        x = Expr::Or(Box::new(x), Box::new(conj(lex)?));
+    }
+
+    Ok(x)
+}
+
+fn conj(lex: &mut Lexer) -> Result<Expr, Error> {
+    let mut x = neg(lex)?;
+
+    while lex.peek() == Some(&Symbol::Word("and")) {
+        let _ = lex.next();
+        x = Expr::And(Box::new(x), Box::new(neg(lex)?));
+    }
+
+    Ok(x)
+}
+
+fn neg(lex: &mut Lexer) -> Result<Expr, Error> {
+    if lex.peek() == Some(&Symbol::Word("not")) {
+        let _ = lex.next();
+        Ok(Expr::Not(Box::new(neg(lex)?)))
+    } else {
+        atom(lex)
+    }
+}
+
+fn atom(lex: &mut Lexer) -> Result<Expr, Error> {
+    if lex.peek() == Some(&Symbol::Punct("(")) {
+        let _ = lex.next();
+        let x = expr(lex)?;
+        expect(lex, Symbol::Punct(")"), "')'")?;
+        return Ok(x);
+    }
+
+    let x = opnd(lex)?;
+
+    let op = match lex.peek() {
+        Some(&Symbol::Punct("==")) => Op::Eq,
+        Some(&Symbol::Punct("!=")) => Op::Ne,
+        Some(&Symbol::Punct("<"))  => Op::Lt,
+        Some(&Symbol::Punct("<=")) => Op::Le,
+        Some(&Symbol::Punct(">"))  => Op::Gt,
+        Some(&Symbol::Punct(">=")) => Op::Ge,
+        _ => return Ok(x),
+    };
+
+    let _ = lex.next();
+    let y = opnd(lex)?;
+
+    Ok(Expr::Compare(op, Box::new(x), Box::new(y)))
+}
+
+fn opnd(lex: &mut Lexer) -> Result<Expr, Error> {
+    if is_literal(lex.peek()) {
+        return Ok(Expr::Literal(lit(lex)?));
+    }
+
+    match lex.next() {
+        Some(Symbol::Word(word)) if word != "and" && word != "or" => {
+            Ok(Expr::Path(word.into()))
+        }
+        x => unexpected(lex, "path, literal or '('", x),
+    }
+}
+
+fn lit(lex: &mut Lexer) -> Result<Value<'static>, Error> {
+    let sym = lex.next();
+
+    Ok(match sym {
+        Some(Symbol::Str(s)) => Value::Str(unescape(s).into()),
+        Some(Symbol::Word("true")) => Value::Bool(true),
+        Some(Symbol::Word("false")) => Value::Bool(false),
+        Some(Symbol::Word(word)) if is_number(word) => {
+            if let Ok(n) = word.parse() {
+                Value::Int(n)
+            } else if let Ok(n) = word.parse() {
+                Value::Float(n)
+            } else {
+                return unexpected(lex, "number", sym);
+            }
+        }
+        x => {
+            return unexpected(lex, "literal", x);
+        }
+    })
+}
+
+fn is_literal(sym: Option<&Symbol>) -> bool {
+    match sym {
+        Some(&Symbol::Str(_)) => true,
+        Some(&Symbol::Word(word)) => {
+            word == "true" || word == "false" || is_number(word)
+        }
+        _ => false,
+    }
+}
+
+fn is_number(word: &str) -> bool {
+    let digits = word.trim_start_matches('-');
+    digits.len() + 1 >= word.len()
+        && digits.starts_with(|c: char| c.is_ascii_digit())
+}
+
+fn unescape(s: &str) -> String {
+    let mut res   = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => res.push('\n'),
+                Some('t') => res.push('\t'),
+                Some(c) => res.push(c),
+                None => res.push('\\'),
+            }
+        } else {
+            res.push(c);
+        }
+    }
+
+    res
 }
 
 fn ident<'a>(lex: &mut Lexer<'a>) -> Result<&'a str, Error> {
@@ -217,13 +699,13 @@ fn ident<'a>(lex: &mut Lexer<'a>) -> Result<&'a str, Error> {
         }
     }
 
-    unexpected("ident", sym)
+    unexpected(lex, "ident", sym)
 }
 
 fn path<'a>(lex: &mut Lexer<'a>) -> Result<&'a str, Error> {
     match lex.next() {
         Some(Symbol::Word(path)) => Ok(path),
-        x => unexpected("path", x),
+        x => unexpected(lex, "path", x),
     }
 }
 
@@ -233,7 +715,7 @@ fn expect(lex: &mut Lexer, sym: Symbol, expected: &'static str) -> Result<(), Er
     if found == Some(sym) {
         Ok(())
     } else {
-        unexpected(expected, found)
+        unexpected(lex, expected, found)
     }
 }
 
@@ -246,22 +728,59 @@ enum Symbol<'a> {
     // Raw HTML.
     Text(&'a str),
     // A word inside the braces.
-    // Guaranteed not to contain a space.
+    // Guaranteed not to contain whitespace.
     Word(&'a str),
+    // Punctuation inside the braces.
+    Punct(&'a str),
+    // A string literal inside the braces, without its quotes.
+    Str(&'a str),
+    // A comment that's never closed, which takes up the rest of the source.
+    Comment,
 }
 
+/// A region of the template source.
+///
+/// `start` and `end` are byte offsets, while `line` and `column` are the
+/// one-based position of `start`, with columns counted in characters.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+const PUNCT: [char; 9] = ['(', ')', '=', '!', '<', '>', ',', '|', '?'];
+
 struct Lexer<'a> {
     src: &'a str,
+    pos: usize,
+    line: usize,
+    column: usize,
     txt: bool,
-    cur: Option<Symbol<'a>>,
+    trim: bool,
+    cur: Option<(Symbol<'a>, Span)>,
+    last: Span,
+    full: &'a str,
+    // The HTML around the current position, for escaping variables.
+    html: Tracker,
 }
 
 impl<'a> Lexer<'a> {
     fn new(src: &'a str) -> Lexer<'a> {
+        let start = Span { start: 0, end: 0, line: 1, column: 1 };
+
         let mut res = Lexer {
             src,
+            pos: 0,
+            line: 1,
+            column: 1,
             txt: true,
+            trim: false,
             cur: None,
+            last: start,
+            full: src,
+            html: Tracker::new(),
         };
 
         assert_eq!(res.next(), None);
@@ -269,110 +788,431 @@ impl<'a> Lexer<'a> {
     }
 
     fn peek(&self) -> Option<&Symbol<'a>> {
-        self.cur.as_ref()
+        self.cur.as_ref().map(|(sym, _)| sym)
+    }
+
+    /// The span of the symbol most recently returned by `next`.
+    fn span(&self) -> Span {
+        self.last
+    }
+
+    /// Consumes `n` bytes of the remaining source.
+    fn bump(&mut self, n: usize) -> &'a str {
+        let (head, rest) = self.src.split_at(n);
+
+        for c in head.chars() {
+            if c == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
+        }
+
+        self.src = rest;
+        self.pos += n;
+        head
+    }
+
+    fn here(&self) -> Span {
+        Span {
+            start: self.pos,
+            end: self.pos,
+            line: self.line,
+            column: self.column,
+        }
     }
 
     fn next(&mut self) -> Option<Symbol<'a>> {
-        let mut next = if self.src.is_empty() {
+        if !self.txt || self.trim {
+            // Ignore whitespace.
+            let n = self.src.len() - self.src.trim_start().len();
+            self.bump(n);
+            self.trim = false;
+        }
+
+        if self.txt {
+            // Skip comments, unless they're unterminated.
+            while self.src.starts_with("{#") {
+                match self.src[2..].find("#}") {
+                    Some(i) => self.bump(i + 4),
+                    None => break,
+                };
+            }
+        }
+
+        let mut span = self.here();
+
+        let next = if self.src.is_empty() {
             // Nothing to process.
             None
         } else if self.txt {
             // Text mode (not inside braces.)
 
-            Some(if self.src.starts_with("{{") {
+            Some(if self.src.starts_with("{#") {
+                // An unterminated comment, since the others were skipped.
+                let n = self.src.len();
+                self.bump(n);
+                Symbol::Comment
+            } else if self.src.starts_with("{{") {
                 // Escaped open brace.
-                self.src = &self.src[2..];
+                self.bump(2);
                 Symbol::Text("{")
             } else if self.src.starts_with("}}") {
                 // Escaped close brace.
-                self.src = &self.src[2..];
+                self.bump(2);
                 Symbol::Text("}")
-            } else if self.src.starts_with("{") {
+            } else if self.src.starts_with("{-") {
+                // Open brace, trimming the text before it.
+                self.bump(2);
+                self.txt = false;
+                Symbol::Open
+            } else if self.src.starts_with('{') {
                 // Open brace (exit text mode.)
-                self.src = &self.src[1..];
+                self.bump(1);
                 self.txt = false;
                 Symbol::Open
-            } else if self.src.starts_with("}") {
+            } else if self.src.starts_with('}') {
                 // Close brace (should be an error.)
-                self.src = &self.src[1..];
+                self.bump(1);
                 Symbol::Close
             } else {
                 // Raw HTML upto the next brace / EOF.
                 let i = self.src.find(&['{', '}'][..])
                     .unwrap_or(self.src.len());
-                let (text, rest) = self.src.split_at(i);
-                self.src = rest;
-                Symbol::Text(text)
+                let text = self.bump(i);
+
+                if self.src.starts_with("{-") {
+                    Symbol::Text(text.trim_end())
+                } else {
+                    Symbol::Text(text)
+                }
             })
         } else {
             // Inside braces.
 
-            // Ignore whitespace.
-            self.src = self.src.trim_left();
-
-            Some(if self.src.starts_with("{") {
+            Some(if self.src.starts_with('{') {
                 // Another open brace (should be an error.)
-                self.src = &self.src[1..];
+                self.bump(1);
                 Symbol::Open
-            } else if self.src.starts_with("}") {
+            } else if self.src.starts_with('}') {
                 // A close brace (re-enter text mode.)
-                self.src = &self.src[1..];
+                self.bump(1);
                 self.txt = true;
                 Symbol::Close
+            } else if self.src.starts_with("-}") {
+                // A close brace, trimming the text after it.
+                self.bump(2);
+                self.txt = true;
+                self.trim = true;
+                Symbol::Close
+            } else if self.src.starts_with('"') {
+                // A string literal, or a lone quote if it's unterminated.
+                let mut escaped = false;
+                let end = self.src[1..].find(|c| {
+                    let end = c == '"' && !escaped;
+                    escaped = c == '\\' && !escaped;
+                    end
+                });
+
+                match end {
+                    Some(i) => {
+                        let s = self.bump(i + 2);
+                        Symbol::Str(&s[1..i + 1])
+                    }
+                    None => Symbol::Punct(self.bump(1)),
+                }
+            } else if ["==", "!=", "<=", ">="].iter().any(|x| self.src.starts_with(x)) {
+                // A two character operator.
+                Symbol::Punct(self.bump(2))
+            } else if self.src.starts_with(&PUNCT[..]) {
+                // A parenthesis, comma, pipe, question mark or one character operator.
+                Symbol::Punct(self.bump(1))
             } else {
-                // Word until next whitespace / brace / EOF.
-                let i = self.src.find(['{', '}', ' '].as_ref())
-                    .unwrap_or(self.src.len());
-                let (word, rest) = self.src.split_at(i);
-                self.src = rest;
-                Symbol::Word(word)
+                // Word until next whitespace / brace / punctuation / EOF.
+                let src = self.src;
+                let i = src
+                    .char_indices()
+                    .find(|&(i, c)| {
+                        c == '{' || c == '}' || c == '"' || PUNCT.contains(&c)
+                            || c.is_whitespace() || src[i..].starts_with("-}")
+                    })
+                    .map_or(src.len(), |(i, _)| i);
+                Symbol::Word(self.bump(i))
             })
         };
 
+        span.end = self.pos;
+
+        let mut next = next.map(|sym| (sym, span));
         mem::swap(&mut next, &mut self.cur);
-        next
+
+        match next {
+            Some((sym, span)) => {
+                self.last = span;
+                Some(sym)
+            }
+            None => {
+                self.last = span;
+                None
+            }
+        }
+    }
+
+    /// Reads the text up to the `{ end }` tag as is, ignoring any braces, and
+    /// leaves the tag to be lexed as usual. Must be called right after a '}'.
+    fn raw(&mut self, end: &str) -> Option<&'a str> {
+        // Starts right after the '}', since the symbol after it has been lexed
+        // already, possibly skipping a comment or whitespace along the way.
+        let close = self.last;
+        let strip = self.full[..close.end].ends_with("-}");
+
+        let from = Span {
+            start: close.end,
+            end: close.end,
+            line: close.line,
+            column: close.column + (close.end - close.start),
+        };
+
+        let rest = &self.full[from.start..];
+
+        let i = rest.match_indices('{').map(|(i, _)| i).find(|&i| {
+            let tag = rest[i + 1..].trim_start_matches('-').trim_start();
+            tag.starts_with(end) && tag[end.len()..]
+                .starts_with(|c: char| c == '}' || c == '-' || c.is_whitespace())
+        })?;
+
+        // Rewind to the start of the text.
+        self.src = rest;
+        self.pos = from.start;
+        self.line = from.line;
+        self.column = from.column;
+        self.txt = true;
+        self.trim = false;
+
+        let mut text = self.bump(i);
+
+        if strip {
+            text = text.trim_start();
+        }
+
+        if self.src.starts_with("{-") {
+            text = text.trim_end();
+        }
+
+        // Lex the opening brace of the tag.
+        self.cur = None;
+        let _ = self.next();
+
+        Some(text)
+    }
+
+    /// The full line of source containing `span`.
+    fn line_of(&self, span: Span) -> &'a str {
+        let start = self.full[..span.start]
+            .rfind('\n')
+            .map_or(0, |i| i + 1);
+        let end = self.full[span.start..]
+            .find('\n')
+            .map_or(self.full.len(), |i| span.start + i);
+
+        self.full[start..end].trim_end_matches('\r')
     }
 }
 
 #[derive(Debug)]
-pub enum Error {
+pub enum ErrorKind {
     Unexpected(&'static str, String),
+    UnterminatedComment,
+}
+
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    span: Span,
+    line: String,
+    file: Option<String>,
 }
 
-fn unexpected<'a, T: Borrow<Symbol<'a>>, U>(
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// The source line containing the error.
+    pub fn line(&self) -> &str {
+        &self.line
+    }
+
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// Records the file the template was loaded from, for display purposes.
+    pub fn with_file<S: Into<String>>(mut self, file: S) -> Error {
+        self.file = Some(file.into());
+        self
+    }
+}
+
+fn unexpected<'a, U>(
+    lex: &Lexer<'a>,
     expected: &'static str,
-    found: Option<T>
+    found: Option<Symbol<'a>>,
 ) -> Result<U, Error>
+{
+    Err(error(lex, expected, found))
+}
+
+fn error<'a>(
+    lex: &Lexer<'a>,
+    expected: &'static str,
+    found: Option<Symbol<'a>>,
+) -> Error
 {
     let found = match found {
-        Some(found) => {
-            match found.borrow() {
-                &Symbol::Open    => "'{'",
-                &Symbol::Close   => "'}'",
-                &Symbol::Text(s) => s,
-                &Symbol::Word(s) => s,
-            }
-        },
+        Some(Symbol::Open)    => "'{'",
+        Some(Symbol::Close)   => "'}'",
+        Some(Symbol::Text(s)) => s,
+        Some(Symbol::Word(s)) => s,
+        Some(Symbol::Punct(s)) => s,
+        Some(Symbol::Str(s)) => s,
+        Some(Symbol::Comment) => "'{#'",
         None => "nothing",
     };
 
-    Err(Error::Unexpected(expected, found.into()))
+    let span = lex.span();
+
+    Error {
+        kind: ErrorKind::Unexpected(expected, found.into()),
+        span,
+        line: lex.line_of(span).into(),
+        file: None,
+    }
 }
 
 impl StdError for Error {
     fn description(&self) -> &str {
-        match self {
-            &Error::Unexpected(_, _) => "unexpected input",
+        match self.kind {
+            ErrorKind::Unexpected(_, _) => "unexpected input",
+            ErrorKind::UnterminatedComment => "unterminated comment",
         }
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            &Error::Unexpected(a, ref b) => {
-                write!(f, "expected {}, found {}", a, b)
+        write!(
+            f,
+            "{}:{}:{}: ",
+            self.file().unwrap_or("<template>"),
+            self.span.line,
+            self.span.column,
+        )?;
+
+        match self.kind {
+            ErrorKind::Unexpected(a, ref b) => {
+                write!(f, "expected {}, found {}", a, b)?;
+            }
+            ErrorKind::UnterminatedComment => {
+                write!(f, "unterminated comment")?;
             }
         }
+
+        // Point at the offending input, keeping tabs so the caret lines up.
+        let lead: String = self.line
+            .chars()
+            .take(self.span.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        let width = self.line
+            .chars()
+            .skip(self.span.column - 1)
+            .take(self.span.end - self.span.start)
+            .count()
+            .max(1);
+
+        write!(f, "\n    {}\n    {}{}", self.line, lead, "^".repeat(width))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The line, column and found symbol of each error in the template.
+    fn errors(src: &str) -> Vec<(usize, usize, String)> {
+        match Template::compile_all(src) {
+            Ok(_) => Vec::new(),
+            Err(errors) => errors
+                .iter()
+                .map(|e| {
+                    let found = match *e.kind() {
+                        ErrorKind::Unexpected(_, ref found) => found.clone(),
+                        ErrorKind::UnterminatedComment => "comment".into(),
+                    };
+
+                    (e.span().line, e.span().column, found)
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn skips_the_rest_of_a_bad_tag() {
+        assert_eq!(errors("{ baz \"q\" ( } ok"), vec![(1, 7, "q".into())]);
+        assert_eq!(errors("{ a b c } { d . ( }"), vec![(1, 5, "b".into()), (1, 15, ".".into())]);
+    }
+
+    #[test]
+    fn reports_every_bad_tag() {
+        let src = "{ if }\n{ x }\n{ end }\n{ for }\n{ end }\n{ y z }";
+
+        assert_eq!(errors(src), vec![
+            (1, 6, "'}'".into()),
+            (4, 7, "'}'".into()),
+            (6, 5, "z".into()),
+        ]);
+    }
+
+    #[test]
+    fn reports_the_end_once() {
+        assert_eq!(errors("{ if x }{ for y in z }{ y"), vec![(1, 26, "nothing".into())]);
+        assert_eq!(errors("{ if x }{ for y in z }"), vec![(1, 23, "nothing".into())]);
+        assert_eq!(errors("{ block a }{ if"), vec![(1, 16, "nothing".into())]);
+    }
+
+    #[test]
+    fn reports_unterminated_comments() {
+        assert_eq!(errors("a {# b } { c }"), vec![(1, 3, "comment".into())]);
+        assert_eq!(errors("{ if x }\n  {# b #} {# c"), vec![(2, 11, "comment".into())]);
+
+        let error = Template::compile("a {# b").unwrap_err();
+        assert!(error.to_string().starts_with("<template>:1:3: unterminated comment"));
+    }
+
+    /// The text of a template that's all content.
+    fn content(src: &str) -> String {
+        Template::compile(src).unwrap().body().iter().map(|x| match *x {
+            Statement::Content { ref content } => &**content,
+            _ => panic!("not content: {:?}", x),
+        }).collect()
+    }
+
+    #[test]
+    fn verbatim_is_kept_as_is() {
+        assert_eq!(content("{ verbatim }{# keep #}{ x }{ endverbatim }"), "{# keep #}{ x }");
+        assert_eq!(content("{ verbatim } { x } { endverbatim }"), " { x } ");
+        assert_eq!(content("a { verbatim -}\n  { x }\n{- endverbatim } b"), "a { x } b");
+    }
+
+    #[test]
+    fn ok_templates_have_no_errors() {
+        assert!(errors("{ if x }{ y }{ else }z{ end }").is_empty());
     }
 }
@@ -1,8 +1,10 @@
-use context::Context;
+use context::{self, Context};
 use error::{Error, Result};
+use escape::Escape;
 use path::Path;
 use render::Chomp;
 use serde_json::Value;
+use std::borrow::Cow;
 use std::io::Write;
 
 impl<W: Write> Context<W> for Value {
@@ -29,7 +31,7 @@ impl<W: Write> Context<W> for Value {
         }
     }
 
-    fn inject(&self, path: Path, sink: &mut W) -> Result<()> {
+    fn inject(&self, path: Path, escape: Escape, sink: &mut W) -> Result<()> {
         use self::Value::*;
 
         let mut value = self;
@@ -56,7 +58,7 @@ impl<W: Write> Context<W> for Value {
             }
 
             String(ref s) => {
-                s.inject(Path::new(""), sink)?;
+                s.inject(Path::new(""), escape, sink)?;
             }
 
             Array(_) | Object(_) => {
@@ -79,12 +81,40 @@ impl<W: Write> Context<W> for Value {
         }
 
         if let &Value::Array(ref array) = value {
+            chomp.length(array.len());
+
             for value in array {
                 chomp.chomp(value)?;
             }
             Ok(())
+        } else if let Value::Object(ref map) = *value {
+            chomp.length(map.len());
+
+            for (key, value) in map {
+                chomp.chomp_entry(key, value)?;
+            }
+            Ok(())
         } else {
             Err(Error::NotIterable(path.to_owned()))
         }
     }
+
+    fn value(&self, path: Path) -> Option<context::Value<'_>> {
+        use self::Value::*;
+
+        let mut value = self;
+
+        for part in path.parts() {
+            value = value.get(part)?;
+        }
+
+        match *value {
+            Bool(b) => Some(context::Value::Bool(b)),
+            Number(ref n) => n.as_i64()
+                .map(context::Value::Int)
+                .or_else(|| n.as_f64().map(context::Value::Float)),
+            String(ref s) => Some(context::Value::Str(Cow::Borrowed(s))),
+            Null | Array(_) | Object(_) => None,
+        }
+    }
 }
@@ -1,8 +1,10 @@
 use error::{Error, Result};
+use escape::Escape;
 use path::Path;
 use render::Chomp;
-use htmlescape;
-use std::borrow::Borrow;
+use std::borrow::{Borrow, Cow};
+use std::cmp::Ordering;
+use std::convert::TryFrom;
 use std::io::Write;
 
 #[cfg(feature = "serde_json")]
@@ -12,8 +14,135 @@ mod toml;
 
 pub trait Context<W> {
     fn truthy(&self, path: Path) -> bool;
-    fn inject(&self, path: Path, sink: &mut W) -> Result<()>;
+    fn inject(&self, path: Path, escape: Escape, sink: &mut W) -> Result<()>;
     fn iterate(&self, path: Path, chomp: Chomp<W>) -> Result<()>;
+    /// Extracts a scalar value for comparisons, if there is one. Without
+    /// it, comparisons against the context are always false.
+    fn value(&self, _: Path) -> Option<Value<'_>> {
+        None
+    }
+}
+
+/// A value, either extracted from a context, written in a template or
+/// produced by a filter.
+#[derive(Clone, Debug)]
+pub enum Value<'a> {
+    /// The absence of a value, e.g. the input of a filter on an undefined path.
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Str(Cow<'a, str>),
+    List(Vec<Value<'a>>),
+}
+
+impl<'a> Value<'a> {
+    pub fn truthy(&self) -> bool {
+        match *self {
+            Value::Null => false,
+            Value::Bool(b) => b,
+            Value::Int(n) => n != 0,
+            Value::Float(n) => n != 0.0,
+            Value::Str(ref s) => !s.is_empty(),
+            Value::List(_) => true,
+        }
+    }
+
+    /// Compares values of the same type. Integers and floats count as the
+    /// same type, while anything else is incomparable.
+    pub fn compare(&self, other: &Value) -> Option<Ordering> {
+        use self::Value::*;
+
+        match (self, other) {
+            (&Bool(a), &Bool(b)) => Some(a.cmp(&b)),
+            (&Int(a), &Int(b)) => Some(a.cmp(&b)),
+            (&Int(a), &Float(b)) => (a as f64).partial_cmp(&b),
+            (&Float(a), &Int(b)) => a.partial_cmp(&(b as f64)),
+            (&Float(a), &Float(b)) => a.partial_cmp(&b),
+            (&Str(ref a), &Str(ref b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+
+    /// Borrows the value, without copying any string.
+    pub fn borrowed(&self) -> Value<'_> {
+        match *self {
+            Value::Str(ref s) => Value::Str(Cow::Borrowed(s)),
+            Value::List(ref items) => {
+                Value::List(items.iter().map(Value::borrowed).collect())
+            }
+            Value::Null => Value::Null,
+            Value::Bool(b) => Value::Bool(b),
+            Value::Int(n) => Value::Int(n),
+            Value::Float(n) => Value::Float(n),
+        }
+    }
+
+    pub fn into_owned(self) -> Value<'static> {
+        match self {
+            Value::Str(s) => Value::Str(Cow::Owned(s.into_owned())),
+            Value::List(items) => {
+                Value::List(items.into_iter().map(Value::into_owned).collect())
+            }
+            Value::Null => Value::Null,
+            Value::Bool(b) => Value::Bool(b),
+            Value::Int(n) => Value::Int(n),
+            Value::Float(n) => Value::Float(n),
+        }
+    }
+}
+
+impl<'a, W: Write> Context<W> for Value<'a> {
+    fn truthy(&self, path: Path) -> bool {
+        match path.parts().next() {
+            Some(_) => false,
+            None => Value::truthy(self),
+        }
+    }
+
+    fn inject(&self, path: Path, escape: Escape, sink: &mut W) -> Result<()> {
+        if path.parts().next().is_some() {
+            return Err(Error::Undefined(path.to_owned()));
+        }
+
+        match *self {
+            Value::Null => {
+                return Err(Error::Undefined(path.to_owned()));
+            }
+            Value::Bool(b) => b.inject(path, escape, sink)?,
+            Value::Int(n) => n.inject(path, escape, sink)?,
+            Value::Float(n) => n.inject(path, escape, sink)?,
+            Value::Str(ref s) => s.inject(path, escape, sink)?,
+            Value::List(_) => {
+                return Err(Error::NotInjectable(path.to_owned()));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn iterate(&self, path: Path, mut chomp: Chomp<W>) -> Result<()> {
+        match (path.parts().next(), self) {
+            (None, &Value::List(ref items)) => {
+                chomp.length(items.len());
+
+                for item in items {
+                    chomp.chomp(item)?;
+                }
+                Ok(())
+            }
+            (None, _) => Err(Error::NotIterable(path.to_owned())),
+            (Some(_), _) => Err(Error::Undefined(path.to_owned())),
+        }
+    }
+
+    fn value(&self, path: Path) -> Option<Value<'_>> {
+        match (path.parts().next(), self) {
+            (None, &Value::Null) => None,
+            (None, _) => Some(self.borrowed()),
+            (Some(_), _) => None,
+        }
+    }
 }
 
 impl<'a, W, T: Context<W> + ?Sized> Context<W> for &'a T {
@@ -21,8 +150,12 @@ impl<'a, W, T: Context<W> + ?Sized> Context<W> for &'a T {
         (*self).truthy(path)
     }
 
-    fn inject(&self, path: Path, sink: &mut W) -> Result<()> {
-        (*self).inject(path, sink)
+    fn value(&self, path: Path) -> Option<Value<'_>> {
+        (*self).value(path)
+    }
+
+    fn inject(&self, path: Path, escape: Escape, sink: &mut W) -> Result<()> {
+        (*self).inject(path, escape, sink)
     }
 
     fn iterate(&self, path: Path, chomp: Chomp<W>) -> Result<()> {
@@ -39,9 +172,9 @@ impl<W: Write, C: Context<W>> Context<W> for Option<C> {
         }
     }
 
-    fn inject(&self, path: Path, sink: &mut W) -> Result<()> {
+    fn inject(&self, path: Path, escape: Escape, sink: &mut W) -> Result<()> {
         if let Some(ref c) = *self {
-            c.inject(path, sink)
+            c.inject(path, escape, sink)
         } else {
             Err(Error::Undefined(path.to_owned()))
         }
@@ -54,6 +187,10 @@ impl<W: Write, C: Context<W>> Context<W> for Option<C> {
             Err(Error::Undefined(path.to_owned()))
         }
     }
+
+    fn value(&self, path: Path) -> Option<Value<'_>> {
+        self.as_ref().and_then(|c| c.value(path))
+    }
 }
 
 impl<W: Write> Context<W> for str {
@@ -64,12 +201,11 @@ impl<W: Write> Context<W> for str {
         }
     }
 
-    fn inject(&self, path: Path, sink: &mut W) -> Result<()> {
+    fn inject(&self, path: Path, escape: Escape, sink: &mut W) -> Result<()> {
         match path.parts().next() {
             Some(_) => Err(Error::Undefined(path.to_owned())),
             None => {
-                //TODO: Remember to warn people to only inject into content positions.
-                htmlescape::encode_minimal_w(self, sink)?;
+                escape.write(self, sink)?;
                 Ok(())
             },
         }
@@ -81,6 +217,13 @@ impl<W: Write> Context<W> for str {
             None => Err(Error::NotIterable(path.to_owned())),
         }
     }
+
+    fn value(&self, path: Path) -> Option<Value<'_>> {
+        match path.parts().next() {
+            Some(_) => None,
+            None => Some(Value::Str(Cow::Borrowed(self))),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -96,7 +239,7 @@ where
         <str as Context<W>>::truthy(s, path)
     }
 
-    fn inject(&self, path: Path, sink: &mut W) -> Result<()> {
+    fn inject(&self, path: Path, _: Escape, sink: &mut W) -> Result<()> {
         match path.parts().next() {
             Some(_) => Err(Error::Undefined(path.to_owned())),
             None => {
@@ -109,6 +252,11 @@ where
     fn iterate(&self, path: Path, chomp: Chomp<W>) -> Result<()> {
         self.0.borrow().iterate(path, chomp)
     }
+
+    fn value(&self, path: Path) -> Option<Value<'_>> {
+        let s = self.0.borrow();
+        <str as Context<W>>::value(s, path)
+    }
 }
 
 impl<W: Write> Context<W> for bool {
@@ -119,7 +267,7 @@ impl<W: Write> Context<W> for bool {
         }
     }
 
-    fn inject(&self, path: Path, sink: &mut W) -> Result<()> {
+    fn inject(&self, path: Path, _: Escape, sink: &mut W) -> Result<()> {
         match path.parts().next() {
             Some(_) => Err(Error::Undefined(path.to_owned())),
             None => {
@@ -135,6 +283,13 @@ impl<W: Write> Context<W> for bool {
             None => Err(Error::NotIterable(path.to_owned())),
         }
     }
+
+    fn value(&self, path: Path) -> Option<Value<'_>> {
+        match path.parts().next() {
+            Some(_) => None,
+            None => Some(Value::Bool(*self)),
+        }
+    }
 }
 
 macro_rules! num_impl {
@@ -147,7 +302,7 @@ macro_rules! num_impl {
             }
         }
 
-        fn inject(&self, path: Path, sink: &mut W) -> Result<()> {
+        fn inject(&self, path: Path, _: Escape, sink: &mut W) -> Result<()> {
             match path.parts().next() {
                 Some(_) => Err(Error::Undefined(path.to_owned())),
                 None => {
@@ -163,19 +318,46 @@ macro_rules! num_impl {
                 None => Err(Error::NotIterable(path.to_owned())),
             }
         }
+
+        fn value(&self, path: Path) -> Option<Value<'_>> {
+            match path.parts().next() {
+                Some(_) => None,
+                None => Some(Value::from(*self)),
+            }
+        }
     }
 }
 }
 
 macro_rules! int_impls {
 ( $($x:ty,)* ) => {
-    $(num_impl!($x, 0);)*
+    $(
+        num_impl!($x, 0);
+
+        impl<'a> From<$x> for Value<'a> {
+            fn from(n: $x) -> Value<'a> {
+                // Integers too large for an i64 are compared as floats.
+                match i64::try_from(n) {
+                    Ok(n) => Value::Int(n),
+                    Err(_) => Value::Float(n as f64),
+                }
+            }
+        }
+    )*
 }
 }
 
 macro_rules! float_impls {
 ( $($x:ty,)* ) => {
-    $(num_impl!($x, 0.0);)*
+    $(
+        num_impl!($x, 0.0);
+
+        impl<'a> From<$x> for Value<'a> {
+            fn from(n: $x) -> Value<'a> {
+                Value::Float(n as f64)
+            }
+        }
+    )*
 }
 }
 
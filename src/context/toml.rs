@@ -1,8 +1,10 @@
-use context::Context;
+use context::{self, Context};
 use error::{Error, Result};
+use escape::Escape;
 use path::Path;
 use render::Chomp;
 use toml::Value;
+use std::borrow::Cow;
 use std::io::Write;
 
 impl<W: Write> Context<W> for Value {
@@ -28,7 +30,7 @@ impl<W: Write> Context<W> for Value {
         }
     }
 
-    fn inject(&self, path: Path, sink: &mut W) -> Result<()> {
+    fn inject(&self, path: Path, escape: Escape, sink: &mut W) -> Result<()> {
         use self::Value::*;
 
         let mut value = self;
@@ -43,7 +45,7 @@ impl<W: Write> Context<W> for Value {
 
         match *value {
             String(ref s) => {
-                s.inject(Path::new(""), sink)?;
+                s.inject(Path::new(""), escape, sink)?;
             }
 
             Integer(n) => {
@@ -82,12 +84,42 @@ impl<W: Write> Context<W> for Value {
         }
 
         if let &Value::Array(ref array) = value {
+            chomp.length(array.len());
+
             for value in array {
                 chomp.chomp(value)?;
             }
             Ok(())
+        } else if let Value::Table(ref map) = *value {
+            chomp.length(map.len());
+
+            for (key, value) in map {
+                chomp.chomp_entry(key, value)?;
+            }
+            Ok(())
         } else {
             Err(Error::NotIterable(path.to_owned()))
         }
     }
+
+    fn value(&self, path: Path) -> Option<context::Value<'_>> {
+        use self::Value::*;
+
+        let mut value = self;
+
+        for part in path.parts() {
+            value = value.get(part)?;
+        }
+
+        match *value {
+            String(ref s) => Some(context::Value::Str(Cow::Borrowed(s))),
+            Integer(n) => Some(context::Value::Int(n)),
+            Float(n) => Some(context::Value::Float(n)),
+            Boolean(b) => Some(context::Value::Bool(b)),
+            Datetime(ref date) => {
+                Some(context::Value::Str(Cow::Owned(date.to_string())))
+            }
+            Array(_) | Table(_) => None,
+        }
+    }
 }
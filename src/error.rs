@@ -11,12 +11,60 @@ pub enum Error {
     NotInjectable(PathBuf),
     NotIterable(PathBuf),
     TemplateNotFound(String),
+    IncludeDepth(Vec<String>),
+    IncludeCycle(Vec<String>),
     Undefined(PathBuf),
+    FilterNotFound(String),
+    Filter(String),
+    /// An error raised while rendering the named template.
+    InTemplate(String, Box<Error>),
+    /// An error raised by the statement at the given location. Errors from
+    /// included templates and loop bodies are nested, innermost last.
+    At(Location, Box<Error>),
     // Other Errors
     Compile(CompileError),
+    /// A template file or directory, at the given path, couldn't be read.
+    Read(String, io::Error),
     Io(io::Error),
 }
 
+impl Error {
+    /// Displays the error along with the ones it wraps, like a backtrace:
+    ///
+    /// ```text
+    /// part:2:4: variable 'item.price' undefined
+    ///     from page:3:3
+    ///     from page:2:1
+    /// ```
+    pub fn trace(&self) -> Trace<'_> {
+        Trace(self)
+    }
+
+    /// The error underneath any names and locations attached to it.
+    pub fn root(&self) -> &Error {
+        match *self {
+            Error::InTemplate(_, ref error) | Error::At(_, ref error) => error.root(),
+            _ => self,
+        }
+    }
+}
+
+/// Where a statement starts in a template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// The name the template was registered under, if any.
+    pub template: Option<String>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self.template.as_deref().unwrap_or("<template>");
+        write!(f, "{}:{}:{}", name, self.line, self.column)
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Error {
         Error::Io(e)
@@ -37,11 +85,27 @@ impl StdError for Error {
             &NotInjectable(_) => "variable not injectable",
             &NotIterable(_) => "variable not iterable",
             &TemplateNotFound(_) => "template not found",
+            &IncludeDepth(_) => "include depth exceeded",
+            &IncludeCycle(_) => "include cycle",
             &Undefined(_) => "variable undefined",
+            &FilterNotFound(_) => "filter not found",
+            &Filter(_) => "filter failed",
+            &InTemplate(..) => "error in template",
+            &At(..) => "error in template",
             &Compile(ref error) => error.description(),
+            &Read(..) => "template unreadable",
             &Io(ref error) => error.description(),
         }
     }
+
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            // The wrapped errors are already part of the message.
+            Error::InTemplate(..) | Error::At(..) => self.root().source(),
+            Error::Read(_, ref error) => Some(error),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -52,9 +116,42 @@ impl fmt::Display for Error {
             &NotInjectable(ref path) => write!(f, "variable '{}' not injectable", path),
             &NotIterable(ref path) => write!(f, "variable '{}' not iterable", path),
             &TemplateNotFound(ref name) => write!(f, "template '{}' not found", name),
+            &IncludeDepth(ref chain) => write!(f, "include depth exceeded: {}", chain.join(" -> ")),
+            &IncludeCycle(ref chain) => write!(f, "include cycle: {}", chain.join(" -> ")),
             &Undefined(ref path) => write!(f, "variable '{}' undefined", path),
+            &FilterNotFound(ref name) => write!(f, "filter '{}' not found", name),
+            &Filter(ref msg) => write!(f, "filter failed: {}", msg),
+            // Just the innermost location and the original error, leaving
+            // the rest to `trace`.
+            &InTemplate(ref name, ref error) => write!(f, "in template '{}': {}", name, error),
+            &At(ref location, ref error) => match **error {
+                At(..) => error.fmt(f),
+                _ => write!(f, "{}: {}", location, error),
+            },
             &Compile(ref error) => error.fmt(f),
+            &Read(ref path, _) => write!(f, "couldn't read '{}'", path),
             &Io(ref error) => error.fmt(f),
         }
     }
 }
+
+/// An error with all the errors it wraps, as returned by `Error::trace`.
+#[derive(Debug, Clone, Copy)]
+pub struct Trace<'a>(&'a Error);
+
+impl<'a> fmt::Display for Trace<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.0 {
+            Error::InTemplate(ref name, ref error) => {
+                write!(f, "in template '{}': {}", name, error.trace())
+            }
+            // The innermost location comes first.
+            Error::At(ref location, ref error) => match **error {
+                Error::At(..) => write!(f, "{}\n    from {}", error.trace(), location),
+                _ => write!(f, "{}: {}", location, error.trace()),
+            },
+            Error::Read(ref path, ref error) => write!(f, "couldn't read '{}': {}", path, error),
+            ref error => error.fmt(f),
+        }
+    }
+}
@@ -0,0 +1,741 @@
+use htmlescape;
+use std::fmt;
+use std::io::{self, Write};
+use std::mem;
+use std::sync::Arc;
+
+/// Where in an HTML document a variable is injected, which decides how it's
+/// escaped. The compiler works this out from the text before each variable.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    /// Text content, or a comment.
+    Text,
+    /// An ordinary attribute value, or anywhere else inside a tag.
+    Attr { quote: Quote },
+    /// The value of an attribute that holds a URL, like `href` or `src`.
+    Url { quote: Quote, part: UrlPart },
+    /// JavaScript, either in a `<script>` element or in an event handler
+    /// attribute, and either inside or outside a string literal.
+    Script { attr: Option<Quote>, string: bool },
+    /// CSS, either in a `<style>` element or in a `style` attribute.
+    Style { attr: Option<Quote> },
+}
+
+/// The quotes around an attribute value.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quote {
+    None,
+    Single,
+    Double,
+}
+
+/// How far into a URL a variable is injected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrlPart {
+    /// At the very start, where it decides the scheme.
+    Start,
+    /// After the start, but before any query or fragment.
+    Path,
+    /// In the query or fragment.
+    Query,
+}
+
+/// What unsafe URLs, like `javascript:` ones, are replaced with.
+const UNSAFE_URL: &str = "#unsafe";
+
+/// Escapes the text of injected variables for some output language.
+pub trait Escaper: fmt::Debug + Send + Sync {
+    /// Writes the escaped text. Positions are only meaningful in HTML, and
+    /// other escapers may ignore them.
+    fn escape(&self, text: &str, position: Position, sink: &mut dyn Write) -> io::Result<()>;
+}
+
+/// How to escape a variable, as passed to `Context::inject`.
+#[derive(Debug, Clone, Copy)]
+pub struct Escape<'a> {
+    escaper: &'a dyn Escaper,
+    position: Position,
+}
+
+impl<'a> Escape<'a> {
+    pub fn new(escaper: &'a dyn Escaper, position: Position) -> Escape<'a> {
+        Escape { escaper, position }
+    }
+
+    pub fn position(&self) -> Position {
+        self.position
+    }
+
+    /// Writes escaped text.
+    pub fn write<W: Write>(&self, text: &str, sink: &mut W) -> io::Result<()> {
+        self.escaper.escape(text, self.position, sink)
+    }
+}
+
+/// Escapes HTML according to the position of each variable. The default.
+#[derive(Debug, Clone, Copy)]
+pub struct Html;
+
+impl Escaper for Html {
+    fn escape(&self, text: &str, position: Position, mut sink: &mut dyn Write) -> io::Result<()> {
+        match position {
+            Position::Text => htmlescape::encode_minimal_w(text, &mut sink),
+            Position::Attr { quote } => attr(text, quote, sink),
+            Position::Url { quote, part } => url(text, part, &mut Attr { quote, sink }),
+            Position::Script { attr: None, string } => script(text, string, sink),
+            Position::Script { attr: Some(quote), string } => {
+                script(text, string, &mut Attr { quote, sink })
+            }
+            Position::Style { attr: None } => style(text, sink),
+            Position::Style { attr: Some(quote) } => style(text, &mut Attr { quote, sink }),
+        }
+    }
+}
+
+/// Escapes XML text and attribute values.
+#[derive(Debug, Clone, Copy)]
+pub struct Xml;
+
+impl Escaper for Xml {
+    fn escape(&self, text: &str, _: Position, sink: &mut dyn Write) -> io::Result<()> {
+        let mut last = 0;
+
+        for (i, c) in text.char_indices() {
+            let entity: &[u8] = match c {
+                '&' => b"&amp;",
+                '<' => b"&lt;",
+                '>' => b"&gt;",
+                '"' => b"&quot;",
+                '\'' => b"&apos;",
+                _ => continue,
+            };
+
+            sink.write_all(&text.as_bytes()[last..i])?;
+            sink.write_all(entity)?;
+            last = i + 1;
+        }
+
+        sink.write_all(&text.as_bytes()[last..])
+    }
+}
+
+/// Doesn't escape anything, for plain text, Markdown, config files and such.
+#[derive(Debug, Clone, Copy)]
+pub struct Plain;
+
+impl Escaper for Plain {
+    fn escape(&self, text: &str, _: Position, sink: &mut dyn Write) -> io::Result<()> {
+        sink.write_all(text.as_bytes())
+    }
+}
+
+/// Escapes the contents of a JSON string, without the quotes around it.
+#[derive(Debug, Clone, Copy)]
+pub struct JsonString;
+
+impl Escaper for JsonString {
+    fn escape(&self, text: &str, _: Position, sink: &mut dyn Write) -> io::Result<()> {
+        for c in text.chars() {
+            match c {
+                '"' => sink.write_all(b"\\\"")?,
+                '\\' => sink.write_all(b"\\\\")?,
+                '\n' => sink.write_all(b"\\n")?,
+                '\r' => sink.write_all(b"\\r")?,
+                '\t' => sink.write_all(b"\\t")?,
+                '\u{0}'..='\u{1f}' | '\u{2028}' | '\u{2029}' => {
+                    write!(sink, "\\u{:04X}", c as u32)?;
+                }
+                c => write!(sink, "{}", c)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Escapes LaTeX's special characters.
+#[derive(Debug, Clone, Copy)]
+pub struct Latex;
+
+impl Escaper for Latex {
+    fn escape(&self, text: &str, _: Position, sink: &mut dyn Write) -> io::Result<()> {
+        for c in text.chars() {
+            match c {
+                '&' | '%' | '$' | '#' | '_' | '{' | '}' => write!(sink, "\\{}", c)?,
+                '~' => sink.write_all(b"\\textasciitilde{}")?,
+                '^' => sink.write_all(b"\\textasciicircum{}")?,
+                '\\' => sink.write_all(b"\\textbackslash{}")?,
+                c => write!(sink, "{}", c)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// The usual escaper for files with the given extension, if there is one.
+pub fn for_extension(extension: &str) -> Option<Arc<dyn Escaper>> {
+    Some(match &*extension.to_ascii_lowercase() {
+        "html" | "htm" | "xhtml" => Arc::new(Html),
+        "xml" | "rss" | "atom" | "svg" => Arc::new(Xml),
+        "txt" | "md" | "markdown" | "sh" | "conf" | "cfg" | "ini" |
+        "yaml" | "yml" | "toml" => Arc::new(Plain),
+        "json" => Arc::new(JsonString),
+        "tex" => Arc::new(Latex),
+        _ => return None,
+    })
+}
+
+fn attr(text: &str, quote: Quote, mut sink: &mut dyn Write) -> io::Result<()> {
+    match quote {
+        Quote::None => htmlescape::encode_attribute_w(text, &mut sink),
+        _ => htmlescape::encode_minimal_w(text, &mut sink),
+    }
+}
+
+/// Percent-encodes a URL. At the start, URLs with schemes other than `http`,
+/// `https` and `mailto` are replaced, and in a query everything but letters,
+/// digits and `-._~` is encoded.
+fn url<W: Write + ?Sized>(text: &str, part: UrlPart, sink: &mut W) -> io::Result<()> {
+    if part == UrlPart::Start {
+        if let Some(i) = text.find([':', '/', '?', '#']) {
+            let scheme = text[..i].to_ascii_lowercase();
+
+            if text[i..].starts_with(':')
+                && scheme != "http"
+                && scheme != "https"
+                && scheme != "mailto"
+            {
+                return sink.write_all(UNSAFE_URL.as_bytes());
+            }
+        }
+    }
+
+    for &b in text.as_bytes() {
+        let keep = match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => true,
+            b'-' | b'.' | b'_' | b'~' => true,
+            b'!' | b'#' | b'$' | b'%' | b'&' | b'(' | b')' | b'*' | b'+' |
+            b',' | b'/' | b':' | b';' | b'=' | b'?' | b'@' | b'[' | b']' => {
+                part != UrlPart::Query
+            }
+            _ => false,
+        };
+
+        if keep {
+            sink.write_all(&[b])?;
+        } else {
+            write!(sink, "%{:02X}", b)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Escapes JavaScript as the contents of a string literal, which is quoted
+/// unless it's already inside one. Since that might be a template literal,
+/// `$`, `{` and `}` are escaped too, so that they can't start a substitution.
+fn script<W: Write + ?Sized>(text: &str, string: bool, sink: &mut W) -> io::Result<()> {
+    if !string {
+        sink.write_all(b"\"")?;
+    }
+
+    for c in text.chars() {
+        match c {
+            '\n' => sink.write_all(b"\\n")?,
+            '\r' => sink.write_all(b"\\r")?,
+            '\t' => sink.write_all(b"\\t")?,
+            '\\' => sink.write_all(b"\\\\")?,
+            '"' | '\'' | '`' | '<' | '>' | '&' | '=' | '/' | '$' | '{' | '}' |
+            '\u{0}'..='\u{1f}' | '\u{2028}' | '\u{2029}' => {
+                write!(sink, "\\u{:04X}", c as u32)?;
+            }
+            c => write!(sink, "{}", c)?,
+        }
+    }
+
+    if !string {
+        sink.write_all(b"\"")?;
+    }
+
+    Ok(())
+}
+
+/// Escapes CSS, so that it can only be part of an identifier or a string.
+fn style<W: Write + ?Sized>(text: &str, sink: &mut W) -> io::Result<()> {
+    for c in text.chars() {
+        if c.is_alphanumeric() || c == '-' || c == '_' || c == ' ' || c == '.' {
+            write!(sink, "{}", c)?;
+        } else {
+            write!(sink, "\\{:X} ", c as u32)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Escapes output for an attribute value, after it's been escaped for the
+/// language inside it.
+struct Attr<'a, W: 'a + ?Sized> {
+    quote: Quote,
+    sink: &'a mut W,
+}
+
+impl<'a, W: Write + ?Sized> Write for Attr<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &b in buf {
+            match b {
+                b'&' => self.sink.write_all(b"&amp;")?,
+                b'<' => self.sink.write_all(b"&lt;")?,
+                b'>' => self.sink.write_all(b"&gt;")?,
+                b'"' => self.sink.write_all(b"&quot;")?,
+                b'\'' => self.sink.write_all(b"&#x27;")?,
+                b if self.quote == Quote::None && b < 128 && !b.is_ascii_alphanumeric() => {
+                    write!(self.sink, "&#x{:02X};", b)?;
+                }
+                b => self.sink.write_all(&[b])?,
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sink.flush()
+    }
+}
+
+/// Follows the HTML in a template, to work out the position of each variable.
+///
+/// Each template is followed on its own, from its start, and in the order of
+/// its source, so this is only a guess when the HTML around a variable is
+/// split between templates or between the branches of an `if`.
+#[derive(Debug)]
+pub(crate) struct Tracker {
+    state: State,
+    // The name of the current tag, in lowercase, with a '/' for end tags.
+    tag: String,
+    // The name of the current attribute, in lowercase.
+    attr: String,
+    // How far into a URL attribute value we are.
+    url: UrlPart,
+    // The quote of the JavaScript string literal we're in, and whether the
+    // last character was a backslash.
+    string: Option<char>,
+    escaped: bool,
+    // Whether the last character was a `$` in a template literal.
+    dollar: bool,
+    // For each `${` substitution in a template literal we're in, how many
+    // braces are open inside it.
+    braces: Vec<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Text,
+    Comment,
+    TagName,
+    Tag,
+    AttrName,
+    AfterAttrName,
+    BeforeValue,
+    Value(Quote),
+    Script,
+    Style,
+}
+
+impl Tracker {
+    pub fn new() -> Tracker {
+        Tracker {
+            state: State::Text,
+            tag: String::new(),
+            attr: String::new(),
+            url: UrlPart::Start,
+            string: None,
+            escaped: false,
+            dollar: false,
+            braces: Vec::new(),
+        }
+    }
+
+    /// The position of a variable at this point.
+    pub fn position(&self) -> Position {
+        match self.state {
+            State::Text | State::Comment => Position::Text,
+            State::TagName | State::Tag | State::AttrName | State::AfterAttrName => {
+                Position::Attr { quote: Quote::None }
+            }
+            State::BeforeValue => self.value(Quote::None, UrlPart::Start),
+            State::Value(quote) => self.value(quote, self.url),
+            State::Script => Position::Script {
+                attr: None,
+                string: self.string.is_some(),
+            },
+            State::Style => Position::Style { attr: None },
+        }
+    }
+
+    fn value(&self, quote: Quote, part: UrlPart) -> Position {
+        let attr = &*self.attr;
+
+        if is_url(attr) {
+            Position::Url { quote, part }
+        } else if attr.starts_with("on") {
+            Position::Script {
+                attr: Some(quote),
+                string: self.string.is_some(),
+            }
+        } else if attr == "style" {
+            Position::Style { attr: Some(quote) }
+        } else {
+            Position::Attr { quote }
+        }
+    }
+
+    /// Moves past a variable.
+    pub fn inject(&mut self) {
+        if self.state == State::BeforeValue {
+            self.start_value(Quote::None);
+        }
+
+        if self.url == UrlPart::Start {
+            self.url = UrlPart::Path;
+        }
+    }
+
+    /// Moves past some text.
+    pub fn text(&mut self, text: &str) {
+        for (i, c) in text.char_indices() {
+            let rest = &text[i + c.len_utf8()..];
+
+            match self.state {
+                State::Text => {
+                    if c == '<' && rest.starts_with("!--") {
+                        self.state = State::Comment;
+                    } else if c == '<' && rest.starts_with(|c: char| {
+                        c.is_ascii_alphabetic() || c == '/' || c == '!'
+                    }) {
+                        self.state = State::TagName;
+                        self.tag.clear();
+                    }
+                }
+                State::Comment => {
+                    if c == '>' && text[..i].ends_with("--") {
+                        self.state = State::Text;
+                    }
+                }
+                State::TagName => {
+                    if c == '>' {
+                        self.close_tag();
+                    } else if c.is_whitespace() || (c == '/' && !self.tag.is_empty()) {
+                        self.state = State::Tag;
+                    } else {
+                        self.tag.push(c.to_ascii_lowercase());
+                    }
+                }
+                State::Tag | State::AfterAttrName => {
+                    if c == '>' {
+                        self.close_tag();
+                    } else if c == '=' && self.state == State::AfterAttrName {
+                        self.state = State::BeforeValue;
+                    } else if !c.is_whitespace() && c != '/' {
+                        self.state = State::AttrName;
+                        self.attr.clear();
+                        self.attr.push(c.to_ascii_lowercase());
+                    }
+                }
+                State::AttrName => {
+                    if c == '>' {
+                        self.close_tag();
+                    } else if c == '=' {
+                        self.state = State::BeforeValue;
+                    } else if c.is_whitespace() {
+                        self.state = State::AfterAttrName;
+                    } else if c == '/' {
+                        self.state = State::Tag;
+                    } else {
+                        self.attr.push(c.to_ascii_lowercase());
+                    }
+                }
+                State::BeforeValue => {
+                    if c == '>' {
+                        self.close_tag();
+                    } else if c == '"' {
+                        self.start_value(Quote::Double);
+                    } else if c == '\'' {
+                        self.start_value(Quote::Single);
+                    } else if !c.is_whitespace() {
+                        self.start_value(Quote::None);
+                        self.value_char(c);
+                    }
+                }
+                State::Value(quote) => {
+                    let end = match quote {
+                        Quote::None => c.is_whitespace() || c == '>',
+                        Quote::Single => c == '\'',
+                        Quote::Double => c == '"',
+                    };
+
+                    if !end {
+                        self.value_char(c);
+                    } else if c == '>' {
+                        self.close_tag();
+                    } else {
+                        self.state = State::Tag;
+                    }
+                }
+                State::Script | State::Style => {
+                    let end = if self.state == State::Script {
+                        "/script"
+                    } else {
+                        "/style"
+                    };
+
+                    let closes = c == '<' && rest.get(..end.len())
+                        .is_some_and(|x| x.eq_ignore_ascii_case(end));
+
+                    if closes {
+                        self.state = State::TagName;
+                        self.tag.clear();
+                    } else if self.state == State::Script {
+                        self.script_char(c);
+                    }
+                }
+            }
+        }
+    }
+
+    fn close_tag(&mut self) {
+        self.state = match &*self.tag {
+            "script" => State::Script,
+            "style" => State::Style,
+            _ => State::Text,
+        };
+
+        self.string = None;
+        self.escaped = false;
+        self.dollar = false;
+        self.braces.clear();
+    }
+
+    fn start_value(&mut self, quote: Quote) {
+        self.state = State::Value(quote);
+        self.url = UrlPart::Start;
+        self.string = None;
+        self.escaped = false;
+        self.dollar = false;
+        self.braces.clear();
+    }
+
+    fn value_char(&mut self, c: char) {
+        if is_url(&self.attr) {
+            if c == '?' || c == '#' {
+                self.url = UrlPart::Query;
+            } else if self.url == UrlPart::Start {
+                self.url = UrlPart::Path;
+            }
+        } else if self.attr.starts_with("on") {
+            self.script_char(c);
+        }
+    }
+
+    fn script_char(&mut self, c: char) {
+        if let Some(quote) = self.string {
+            let dollar = mem::replace(&mut self.dollar, false);
+
+            if self.escaped {
+                self.escaped = false;
+            } else if c == '\\' {
+                self.escaped = true;
+            } else if c == quote {
+                self.string = None;
+            } else if quote == '`' && c == '$' {
+                self.dollar = true;
+            } else if quote == '`' && c == '{' && dollar {
+                self.string = None;
+                self.braces.push(0);
+            }
+        } else if c == '"' || c == '\'' || c == '`' {
+            self.string = Some(c);
+        } else if let Some(open) = self.braces.last_mut() {
+            // The end of a substitution goes back into its template literal.
+            match c {
+                '{' => *open += 1,
+                '}' if *open > 0 => *open -= 1,
+                '}' => {
+                    self.braces.pop();
+                    self.string = Some('`');
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Whether the attribute holds a URL.
+fn is_url(attr: &str) -> bool {
+    matches!(
+        attr,
+        "href" | "src" | "action" | "formaction" | "cite" | "poster" |
+        "background" | "longdesc" | "usemap" | "codebase" | "data" |
+        "xlink:href"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Follows `html`, then writes `value` as a variable right after it.
+    fn inject(html: &str, value: &str) -> (Position, String) {
+        let mut tracker = Tracker::new();
+        tracker.text(html);
+
+        let position = tracker.position();
+        let mut out = Vec::new();
+        Html.escape(value, position, &mut out).unwrap();
+
+        (position, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(
+            inject("<p>", "<b>&\"'"),
+            (Position::Text, "&lt;b&gt;&amp;&quot;&#x27;".into()),
+        );
+        assert_eq!(inject("<!-- <a href=", "<").0, Position::Text);
+        assert_eq!(inject("<!-- x --><p>", "a").0, Position::Text);
+    }
+
+    #[test]
+    fn attr() {
+        assert_eq!(
+            inject("<p title=\"", "\"><x>"),
+            (Position::Attr { quote: Quote::Double }, "&quot;&gt;&lt;x&gt;".into()),
+        );
+        assert_eq!(
+            inject("<p title='", "' x='"),
+            (Position::Attr { quote: Quote::Single }, "&#x27; x=&#x27;".into()),
+        );
+        assert_eq!(
+            inject("<p title=", "a b>"),
+            (Position::Attr { quote: Quote::None }, "a&#x20;b&gt;".into()),
+        );
+        assert_eq!(inject("<p ", "x").0, Position::Attr { quote: Quote::None });
+    }
+
+    #[test]
+    fn url() {
+        let start = Position::Url { quote: Quote::Double, part: UrlPart::Start };
+        let path = Position::Url { quote: Quote::Double, part: UrlPart::Path };
+        let query = Position::Url { quote: Quote::Double, part: UrlPart::Query };
+
+        assert_eq!(inject("<a href=\"", "/a b?c\""), (start, "/a%20b?c%22".into()));
+        assert_eq!(inject("<a href=\"", "https://x.y/"), (start, "https://x.y/".into()));
+        assert_eq!(inject("<a href=\"/x/", "a/b"), (path, "a/b".into()));
+        assert_eq!(inject("<a href=\"/x?q=", "a&b=c"), (query, "a%26b%3Dc".into()));
+        assert_eq!(
+            inject("<img src=", "x y").0,
+            Position::Url { quote: Quote::None, part: UrlPart::Start },
+        );
+    }
+
+    #[test]
+    fn unsafe_urls() {
+        for url in &["javascript:alert(1)", "JavaScript:alert(1)", " javascript:x", "data:text/html,x", "vbscript:x"] {
+            assert_eq!(inject("<a href=\"", url).1, UNSAFE_URL);
+        }
+
+        // Only at the start, where the scheme is decided.
+        assert_eq!(inject("<a href=\"/x/", "javascript:x").1, "javascript:x");
+        assert_eq!(inject("<a href=\"", "mailto:a@b").1, "mailto:a@b");
+    }
+
+    #[test]
+    fn script() {
+        assert_eq!(
+            inject("<script>var x = ", "</script><x>"),
+            (
+                Position::Script { attr: None, string: false },
+                "\"\\u003C\\u002Fscript\\u003E\\u003Cx\\u003E\"".into(),
+            ),
+        );
+        assert_eq!(
+            inject("<SCRIPT>var x = 'a", "'\n\\"),
+            (Position::Script { attr: None, string: true }, "\\u0027\\n\\\\".into()),
+        );
+        assert_eq!(inject("<script>var x = \"\\\"", "a").0, Position::Script {
+            attr: None,
+            string: true,
+        });
+        assert_eq!(inject("<script>var x = 'a';</script><p>", "a").0, Position::Text);
+    }
+
+    #[test]
+    fn template_literals() {
+        let (position, out) = inject("<script>var x = `hi ", "${alert(document.cookie)}");
+
+        assert_eq!(position, Position::Script { attr: None, string: true });
+        assert_eq!(out, "\\u0024\\u007Balert(document.cookie)\\u007D");
+
+        // Inside a substitution, it's code again, until the substitution ends.
+        assert_eq!(
+            inject("<script>`${", "alert(1)"),
+            (Position::Script { attr: None, string: false }, "\"alert(1)\"".into()),
+        );
+        assert_eq!(
+            inject("<script>`${ f({}) + '}' ", "x").0,
+            Position::Script { attr: None, string: false },
+        );
+        assert_eq!(
+            inject("<script>`${ {a: 1}.a } $", "x").0,
+            Position::Script { attr: None, string: true },
+        );
+        assert_eq!(
+            inject("<script>`$$`; ", "x").0,
+            Position::Script { attr: None, string: false },
+        );
+    }
+
+    #[test]
+    fn event_handlers() {
+        assert_eq!(
+            inject("<a onclick=\"f('", "');alert(1);('"),
+            (
+                Position::Script { attr: Some(Quote::Double), string: true },
+                "\\u0027);alert(1);(\\u0027".into(),
+            ),
+        );
+        assert_eq!(
+            inject("<a onclick='f(", "\"").1,
+            "&quot;\\u0022&quot;",
+        );
+    }
+
+    #[test]
+    fn style() {
+        assert_eq!(
+            inject("<style>p { color: ", "red;}</style>"),
+            (Position::Style { attr: None }, "red\\3B \\7D \\3C \\2F style\\3E ".into()),
+        );
+        assert_eq!(
+            inject("<p style=\"color: ", "a\"b"),
+            (Position::Style { attr: Some(Quote::Double) }, "a\\22 b".into()),
+        );
+    }
+
+    #[test]
+    fn other_escapers() {
+        let escape = |escaper: &dyn Escaper, text: &str| {
+            let mut out = Vec::new();
+            escaper.escape(text, Position::Text, &mut out).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        assert_eq!(escape(&Xml, "<a b='c'>&"), "&lt;a b=&apos;c&apos;&gt;&amp;");
+        assert_eq!(escape(&Plain, "<a>"), "<a>");
+        assert_eq!(escape(&JsonString, "\"\\\n\u{1}"), "\\\"\\\\\\n\\u0001");
+        assert_eq!(escape(&Latex, "50% & $x_1$"), "50\\% \\& \\$x\\_1\\$");
+    }
+}
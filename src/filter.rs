@@ -0,0 +1,153 @@
+use context::Value;
+use error::{Error, Result};
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+/// Transforms a value before it's injected, as in `{ name | upper }`.
+///
+/// Filters get `Value::Null` when the path they're applied to is undefined,
+/// and most of the built-in ones pass it on, so that it's handled by the
+/// undefined-variable policy.
+pub trait Filter: Send + Sync {
+    fn apply<'a>(&self, value: Value<'a>, args: &[Value<'a>]) -> Result<Value<'a>>;
+}
+
+impl<F> Filter for F
+where
+    F: for<'a> Fn(Value<'a>, &[Value<'a>]) -> Result<Value<'a>> + Send + Sync,
+{
+    fn apply<'a>(&self, value: Value<'a>, args: &[Value<'a>]) -> Result<Value<'a>> {
+        self(value, args)
+    }
+}
+
+/// The filters every `Tenjin` starts out with.
+pub fn builtins() -> HashMap<String, Box<dyn Filter>> {
+    let mut filters: HashMap<String, Box<dyn Filter>> = HashMap::new();
+
+    filters.insert("upper".into(), Box::new(upper));
+    filters.insert("lower".into(), Box::new(lower));
+    filters.insert("trim".into(), Box::new(trim));
+    filters.insert("truncate".into(), Box::new(truncate));
+    filters.insert("default".into(), Box::new(default));
+    filters.insert("length".into(), Box::new(length));
+    filters.insert("join".into(), Box::new(join));
+
+    filters
+}
+
+/// The text of a scalar value.
+fn text<'a>(name: &str, value: Value<'a>) -> Result<Cow<'a, str>> {
+    Ok(match value {
+        Value::Str(s) => s,
+        Value::Bool(b) => Cow::Owned(b.to_string()),
+        Value::Int(n) => Cow::Owned(n.to_string()),
+        Value::Float(n) => Cow::Owned(n.to_string()),
+        Value::Null | Value::List(_) => {
+            let msg = format!("'{}' expects a string", name);
+            return Err(Error::Filter(msg));
+        }
+    })
+}
+
+fn upper<'a>(value: Value<'a>, _: &[Value<'a>]) -> Result<Value<'a>> {
+    if let Value::Null = value {
+        return Ok(Value::Null);
+    }
+
+    Ok(Value::Str(Cow::Owned(text("upper", value)?.to_uppercase())))
+}
+
+fn lower<'a>(value: Value<'a>, _: &[Value<'a>]) -> Result<Value<'a>> {
+    if let Value::Null = value {
+        return Ok(Value::Null);
+    }
+
+    Ok(Value::Str(Cow::Owned(text("lower", value)?.to_lowercase())))
+}
+
+fn trim<'a>(value: Value<'a>, _: &[Value<'a>]) -> Result<Value<'a>> {
+    if let Value::Null = value {
+        return Ok(Value::Null);
+    }
+
+    Ok(Value::Str(match text("trim", value)? {
+        Cow::Borrowed(s) => Cow::Borrowed(s.trim()),
+        Cow::Owned(s) => Cow::Owned(s.trim().into()),
+    }))
+}
+
+/// `truncate n [end]` shortens text to `n` characters, followed by `end`,
+/// which defaults to "...".
+fn truncate<'a>(value: Value<'a>, args: &[Value<'a>]) -> Result<Value<'a>> {
+    if let Value::Null = value {
+        return Ok(Value::Null);
+    }
+
+    let s = text("truncate", value)?;
+
+    let (n, end) = match args {
+        [Value::Int(n)] if *n >= 0 => (*n as usize, "..."),
+        [Value::Int(n), Value::Str(end)] if *n >= 0 => (*n as usize, &**end),
+        _ => {
+            let msg = "'truncate' expects a length and an optional ending";
+            return Err(Error::Filter(msg.into()));
+        }
+    };
+
+    Ok(Value::Str(match s.char_indices().nth(n) {
+        Some((i, _)) => Cow::Owned(format!("{}{}", &s[..i], end)),
+        None => s,
+    }))
+}
+
+/// `default x` replaces undefined values with `x`.
+fn default<'a>(value: Value<'a>, args: &[Value<'a>]) -> Result<Value<'a>> {
+    match (value, args) {
+        (Value::Null, [x]) => Ok(x.clone()),
+        (value, [_]) => Ok(value),
+        _ => Err(Error::Filter("'default' expects a value".into())),
+    }
+}
+
+fn length<'a>(value: Value<'a>, _: &[Value<'a>]) -> Result<Value<'a>> {
+    if let Value::Null = value {
+        return Ok(Value::Null);
+    }
+
+    match value {
+        Value::Str(s) => Ok(Value::from(s.chars().count())),
+        Value::List(items) => Ok(Value::from(items.len())),
+        _ => Err(Error::Filter("'length' expects a string or list".into())),
+    }
+}
+
+/// `join [sep]` joins a list's items with `sep`, which defaults to nothing.
+fn join<'a>(value: Value<'a>, args: &[Value<'a>]) -> Result<Value<'a>> {
+    if let Value::Null = value {
+        return Ok(Value::Null);
+    }
+
+    let sep = match args {
+        [] => "",
+        [Value::Str(sep)] => &**sep,
+        _ => return Err(Error::Filter("'join' expects a separator".into())),
+    };
+
+    let items = match value {
+        Value::List(items) => items,
+        _ => return Err(Error::Filter("'join' expects a list".into())),
+    };
+
+    let mut res = String::new();
+
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            res.push_str(sep);
+        }
+
+        res.push_str(&text("join", item)?);
+    }
+
+    Ok(Value::Str(Cow::Owned(res)))
+}
@@ -8,10 +8,17 @@ mod macros;
 pub mod compile;
 pub mod context;
 pub mod error;
+pub mod escape;
+pub mod filter;
+pub mod load;
+pub mod options;
 pub mod path;
 pub mod render;
 
 pub use context::{Raw, Context};
 pub use error::{Error, Result};
+pub use filter::Filter;
+pub use load::Loader;
+pub use options::{RenderOptions, Undefined};
 pub use render::Tenjin;
 pub use compile::Template;
@@ -0,0 +1,396 @@
+use compile::Template;
+use error::{Error, Result};
+use escape;
+use std::borrow::Borrow;
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::hash::{BuildHasher, Hash};
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+/// A source of templates, which a `Tenjin` asks for any template that hasn't
+/// been registered or loaded already. Whatever it loads is cached.
+///
+/// ```ignore
+/// tenjin.set_loader(Chain::new()
+///     .with(Directory::new("themes/dark"))
+///     .with(Directory::new("templates")));
+/// ```
+pub trait Loader: fmt::Debug + Send + Sync {
+    /// Compiles the named template, or returns `None` if there's no such
+    /// template here.
+    fn load(&self, name: &str) -> Result<Option<Template>>;
+}
+
+/// Compiles the source of a template, blaming `file` for any compile error
+/// and choosing the escaper by its extension.
+pub fn compile(source: &str, file: &str) -> Result<Template> {
+    let mut template = Template::compile(source).map_err(|e| e.with_file(file))?;
+
+    if let Some(ext) = Path::new(file).extension().and_then(|x| x.to_str()) {
+        template.set_escaper(escape::for_extension(ext));
+    }
+
+    Ok(template)
+}
+
+/// How the names of templates are made from the paths of their files,
+/// relative to the directory. Either way, they're separated by forward
+/// slashes on every platform.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Naming {
+    /// `pages/index.html` is named `pages/index`. This is the default.
+    #[default]
+    StripExtension,
+    /// `pages/index.html` is named `pages/index.html`.
+    KeepExtension,
+}
+
+/// Loads templates from the files in a directory, either on demand or all at
+/// once with `load_all`.
+///
+/// ```ignore
+/// let templates = Directory::new("templates")
+///     .extensions(&["html", "xml", "txt"])
+///     .ignore("drafts")
+///     .ignore("*.bak.*")
+///     .load_all()?;
+/// ```
+#[derive(Debug, Clone)]
+pub struct Directory {
+    root: PathBuf,
+    extensions: Vec<String>,
+    ignore: Vec<String>,
+    naming: Naming,
+}
+
+impl Directory {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Directory {
+        Directory {
+            root: root.into(),
+            extensions: vec!["html".into()],
+            ignore: Vec::new(),
+            naming: Naming::default(),
+        }
+    }
+
+    /// Sets the extensions of template files, with or without the dot, which
+    /// defaults to just `html`. When several files only differ by extension,
+    /// the one that comes first here wins.
+    pub fn extensions<I, S>(mut self, extensions: I) -> Directory
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.extensions = extensions
+            .into_iter()
+            .map(|x| x.as_ref().trim_start_matches('.').to_owned())
+            .collect();
+
+        self
+    }
+
+    /// Skips the files and directories that match a glob, where `*` matches
+    /// anything but a slash, `**` matches anything at all, and `?` matches
+    /// any one character but a slash. A glob with a slash in it is matched
+    /// against the whole relative path, e.g. `emails/*.txt`, while one without
+    /// is matched against each file and directory name along it, e.g. `.*`.
+    pub fn ignore<S: Into<String>>(mut self, glob: S) -> Directory {
+        self.ignore.push(glob.into());
+        self
+    }
+
+    /// Sets how templates are named, which defaults to stripping extensions.
+    pub fn naming(mut self, naming: Naming) -> Directory {
+        self.naming = naming;
+        self
+    }
+
+    /// Compiles every template in the directory, along with its name, in
+    /// order of their names. Any template or directory that can't be read is
+    /// an error, rather than being skipped, while other files are never read.
+    pub fn load_all(&self) -> Result<Vec<(String, Template)>> {
+        // The files for each name, with the index of their extension.
+        let mut files = BTreeMap::new();
+        let mut dirs = Vec::new();
+        self.scan(&mut self.root.clone(), &mut Vec::new(), &mut dirs, &mut files)?;
+
+        let mut templates = Vec::with_capacity(files.len());
+
+        for (name, (_, path)) in files {
+            templates.push((name, read(&path)?));
+        }
+
+        Ok(templates)
+    }
+
+    /// Finds the templates in the directory at `path`, which is at `parts`
+    /// relative to the root, and inside the directories at `dirs`.
+    fn scan(
+        &self,
+        path: &mut PathBuf,
+        parts: &mut Vec<String>,
+        dirs: &mut Vec<PathBuf>,
+        files: &mut BTreeMap<String, (usize, PathBuf)>,
+    ) -> Result<()> {
+        // Links are followed, but not round in circles.
+        let dir = fs::canonicalize(&path).map_err(|e| unreadable(path, e))?;
+
+        if dirs.contains(&dir) {
+            let e = io::Error::new(io::ErrorKind::InvalidData, "directory contains itself");
+            return Err(unreadable(path, e));
+        }
+
+        dirs.push(dir);
+        let entries = fs::read_dir(&path).map_err(|e| unreadable(path, e))?;
+
+        for entry in entries {
+            let entry = entry.map_err(|e| unreadable(path, e))?;
+            path.push(entry.file_name());
+
+            // Names that aren't UTF-8 can still be ignored, or not be
+            // templates at all, so they're only a problem further down.
+            parts.push(entry.file_name().to_string_lossy().into_owned());
+            let rel = parts.join("/");
+
+            if !self.ignored(&rel) {
+                let kind = entry.file_type().map_err(|e| unreadable(path, e))?;
+                let name = self.name(&rel);
+
+                let dir = if kind.is_symlink() {
+                    // A broken link only matters if it would be a template.
+                    match fs::metadata(&path) {
+                        Ok(meta) => meta.is_dir(),
+                        Err(_) if name.is_none() => false,
+                        Err(e) => return Err(unreadable(path, e)),
+                    }
+                } else {
+                    kind.is_dir()
+                };
+
+                if dir {
+                    self.scan(path, parts, dirs, files)?;
+                } else if let Some((name, index)) = name {
+                    if path.strip_prefix(&self.root).ok().and_then(|x| x.to_str()).is_none() {
+                        let e = io::Error::new(io::ErrorKind::InvalidData, "name isn't UTF-8");
+                        return Err(unreadable(path, e));
+                    }
+
+                    let file = (index, path.clone());
+
+                    match files.entry(name) {
+                        Entry::Vacant(entry) => {
+                            entry.insert(file);
+                        }
+                        Entry::Occupied(mut entry) => {
+                            if index < entry.get().0 {
+                                entry.insert(file);
+                            }
+                        }
+                    }
+                }
+            }
+
+            parts.pop();
+            path.pop();
+        }
+
+        dirs.pop();
+        Ok(())
+    }
+
+    /// The name of the template in the file at a relative path, and the index
+    /// of its extension, if it's a template at all.
+    fn name(&self, rel: &str) -> Option<(String, usize)> {
+        let ext = Path::new(rel).extension()?.to_str()?;
+        let index = self.extensions.iter().position(|x| x == ext)?;
+
+        let name = match self.naming {
+            Naming::StripExtension => &rel[..rel.len() - ext.len() - 1],
+            Naming::KeepExtension => rel,
+        };
+
+        Some((name.into(), index))
+    }
+
+    /// Whether the file or directory at a relative path is ignored.
+    fn ignored(&self, rel: &str) -> bool {
+        self.ignore.iter().any(|pattern| {
+            if pattern.contains('/') {
+                glob(pattern, rel)
+            } else {
+                rel.split('/').any(|part| glob(pattern, part))
+            }
+        })
+    }
+}
+
+impl Loader for Directory {
+    fn load(&self, name: &str) -> Result<Option<Template>> {
+        // Names can't climb out of the directory.
+        for part in name.split('/') {
+            let mut components = Path::new(part).components();
+
+            match (components.next(), components.next()) {
+                (Some(Component::Normal(_)), None) => {}
+                _ => return Ok(None),
+            }
+        }
+
+        let candidates = match self.naming {
+            Naming::StripExtension => self.extensions
+                .iter()
+                .map(|ext| format!("{}.{}", name, ext))
+                .collect(),
+            Naming::KeepExtension => vec![name.to_owned()],
+        };
+
+        for rel in candidates {
+            if self.name(&rel).is_none() || self.ignored(&rel) {
+                continue;
+            }
+
+            let mut path = self.root.clone();
+            path.extend(rel.split('/'));
+
+            match read(&path) {
+                Err(Error::Read(_, ref e)) if e.kind() == io::ErrorKind::NotFound => {}
+                res => return res.map(Some),
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Reads and compiles the template in a file, noting when it was modified so
+/// that it can be reloaded.
+pub(crate) fn read(path: &Path) -> Result<Template> {
+    // Checked first, so that changes made while reading aren't missed.
+    let modified = modified(path);
+    let source = fs::read_to_string(path).map_err(|e| unreadable(path, e))?;
+
+    let mut template = compile(&source, &path.to_string_lossy())?;
+    template.set_file(path.to_owned(), modified);
+    Ok(template)
+}
+
+/// When a file was last modified, if that can be found out.
+pub(crate) fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|x| x.modified()).ok()
+}
+
+fn unreadable(path: &Path, error: io::Error) -> Error {
+    Error::Read(path.to_string_lossy().into_owned(), error)
+}
+
+/// Whether some text matches a glob, as described by `Directory::ignore`.
+fn glob(pattern: &str, text: &str) -> bool {
+    if let Some(rest) = pattern.strip_prefix("**") {
+        // So that `**/x` matches `x` as well.
+        if let Some(rest) = rest.strip_prefix('/') {
+            if glob(rest, text) {
+                return true;
+            }
+        }
+
+        return text
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain(Some(text.len()))
+            .any(|i| glob(rest, &text[i..]));
+    }
+
+    if let Some(rest) = pattern.strip_prefix('*') {
+        for (i, c) in text.char_indices() {
+            if glob(rest, &text[i..]) {
+                return true;
+            }
+
+            if c == '/' {
+                return false;
+            }
+        }
+
+        return glob(rest, "");
+    }
+
+    let mut p = pattern.chars();
+    let mut t = text.chars();
+
+    match (p.next(), t.next()) {
+        (None, None) => true,
+        (Some('?'), Some(c)) if c != '/' => glob(p.as_str(), t.as_str()),
+        (Some(a), Some(b)) if a == b => glob(p.as_str(), t.as_str()),
+        _ => false,
+    }
+}
+
+/// Loads templates from their sources, keyed by name.
+impl<K, V, S> Loader for HashMap<K, V, S>
+where
+    K: Borrow<str> + Hash + Eq + fmt::Debug + Send + Sync,
+    V: AsRef<str> + fmt::Debug + Send + Sync,
+    S: BuildHasher + Send + Sync,
+{
+    fn load(&self, name: &str) -> Result<Option<Template>> {
+        match self.get(name) {
+            Some(source) => compile(source.as_ref(), name).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Templates baked into the binary, as pairs of names and sources.
+///
+/// ```ignore
+/// static TEMPLATES: Embedded = Embedded(&[
+///     ("index.html", include_str!("../templates/index.html")),
+///     ("feed.xml", include_str!("../templates/feed.xml")),
+/// ]);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct Embedded(pub &'static [(&'static str, &'static str)]);
+
+impl Loader for Embedded {
+    fn load(&self, name: &str) -> Result<Option<Template>> {
+        match self.0.iter().find(|x| x.0 == name) {
+            Some(&(name, source)) => compile(source, name).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Tries each of its loaders in turn, so that e.g. a theme can override
+/// some of the default templates.
+#[derive(Debug, Default)]
+pub struct Chain {
+    loaders: Vec<Box<dyn Loader>>,
+}
+
+impl Chain {
+    pub fn new() -> Chain {
+        Chain::default()
+    }
+
+    /// Adds a loader, to be tried after the ones before it.
+    pub fn with<L: Loader + 'static>(mut self, loader: L) -> Chain {
+        self.loaders.push(Box::new(loader));
+        self
+    }
+}
+
+impl Loader for Chain {
+    fn load(&self, name: &str) -> Result<Option<Template>> {
+        for loader in &self.loaders {
+            if let Some(template) = loader.load(name)? {
+                return Ok(Some(template));
+            }
+        }
+
+        Ok(None)
+    }
+}
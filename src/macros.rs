@@ -63,14 +63,19 @@ macro_rules! context {
             }
         }
 
-        fn inject(&$self, path: $crate::path::Path, sink: &mut ZZZ)
+        fn inject(
+            &$self,
+            path: $crate::path::Path,
+            escape: $crate::escape::Escape,
+            sink: &mut ZZZ,
+        )
             -> $crate::Result<()>
         {
             let mut parts = path.parts();
 
             if let Some(part) = parts.next() {
                 context! {
-                    $self path part parts sink
+                    $self path part parts escape sink
                     __inject_dict__ $($body)*
                 }
             } else {
@@ -92,17 +97,33 @@ macro_rules! context {
                 Err($crate::Error::NotIterable(path.to_owned()))
             }
         }
+
+        fn value(&$self, path: $crate::path::Path)
+            -> Option<$crate::context::Value<'_>>
+        {
+            let mut parts = path.parts();
+
+            if let Some(part) = parts.next() {
+                context! {
+                    $self path part parts
+                    __value_dict__ $($body)*
+                }
+            } else {
+                // Maps have no value.
+                None
+            }
+        }
     };
 
     // INJECTION
 
-    ( $self:ident $path:ident $part:ident $parts:ident $sink:ident
+    ( $self:ident $path:ident $part:ident $parts:ident $escape:ident $sink:ident
       __inject_dict__ $key:ident => @{ $($val:tt)* }, $($body:tt)*
     ) => {
         if stringify!($key) == $part {
             if let Some(part) = $parts.next() {
                 context! {
-                    $self $path part $parts $sink
+                    $self $path part $parts $escape $sink
                     __inject_dict__ $($val)*
                 }
             } else {
@@ -110,13 +131,22 @@ macro_rules! context {
             }
         } else {
             context! {
-                $self $path $part $parts $sink
+                $self $path $part $parts $escape $sink
                 __inject_dict__ $($body)*
             }
         }
     };
 
-    ( $self:ident $path:ident $part:ident $parts:ident $sink:ident
+    ( $self:ident $path:ident $part:ident $parts:ident $escape:ident $sink:ident
+      __inject_dict__ $key:ident => @map $val:expr, $($body:tt)*
+    ) => {
+        context! {
+            $self $path $part $parts $escape $sink
+            __inject_dict__ $key => @iter $val, $($body)*
+        }
+    };
+
+    ( $self:ident $path:ident $part:ident $parts:ident $escape:ident $sink:ident
       __inject_dict__ $key:ident => @iter $val:expr, $($body:tt)*
     ) => {
         if stringify!($key) == $part {
@@ -126,39 +156,39 @@ macro_rules! context {
             })
         } else {
             context! {
-                $self $path $part $parts $sink
+                $self $path $part $parts $escape $sink
                 __inject_dict__ $($body)*
             }
         }
     };
 
-    ( $self:ident $path:ident $part:ident $parts:ident $sink:ident
+    ( $self:ident $path:ident $part:ident $parts:ident $escape:ident $sink:ident
       __inject_dict__ $key:ident => @raw $val:expr, $($body:tt)*
     ) => {
         if stringify!($key) == $part {
-            $crate::Raw($val).inject($parts.as_path(), $sink)
+            $crate::Raw($val).inject($parts.as_path(), $escape, $sink)
         } else {
             context! {
-                $self $path $part $parts $sink
+                $self $path $part $parts $escape $sink
                 __inject_dict__ $($body)*
             }
         }
     };
 
-    ( $self:ident $path:ident $part:ident $parts:ident $sink:ident
+    ( $self:ident $path:ident $part:ident $parts:ident $escape:ident $sink:ident
       __inject_dict__ $key:ident => $val:expr, $($body:tt)*
     ) => {
         if stringify!($key) == $part {
-            $val.inject($parts.as_path(), $sink)
+            $val.inject($parts.as_path(), $escape, $sink)
         } else {
             context! {
-                $self $path $part $parts $sink
+                $self $path $part $parts $escape $sink
                 __inject_dict__ $($body)*
             }
         }
     };
 
-    ( $self:ident $path:ident $part:ident $parts:ident $sink:ident
+    ( $self:ident $path:ident $part:ident $parts:ident $escape:ident $sink:ident
       __inject_dict__
     ) => {
         Err($crate::Error::Undefined($path.to_owned()))
@@ -192,7 +222,15 @@ macro_rules! context {
         if stringify!($key) == $part {
             match $parts.next() {
                 None => {
-                    for item in $val {
+                    let iter = ::std::iter::IntoIterator::into_iter($val);
+
+                    if let (lower, Some(upper)) = iter.size_hint() {
+                        if lower == upper {
+                            $chomp.length(lower);
+                        }
+                    }
+
+                    for item in iter {
                         $chomp.chomp(item)?;
                     }
                     Ok(())
@@ -207,6 +245,40 @@ macro_rules! context {
         }
     };
 
+    ( $self:ident $path:ident $part:ident $parts:ident $chomp:ident
+      __iterate_dict__ $key:ident => @map $val:expr, $($body:tt)*
+    ) => {
+        if stringify!($key) == $part {
+            match $parts.next() {
+                None => {
+                    // Sort the entries, since maps may be unordered.
+                    let mut entries: Vec<_> =
+                        ::std::iter::IntoIterator::into_iter($val).collect();
+
+                    entries.sort_by(|a, b| {
+                        let a = ::std::convert::AsRef::<str>::as_ref(&a.0);
+                        let b = ::std::convert::AsRef::<str>::as_ref(&b.0);
+                        a.cmp(b)
+                    });
+
+                    $chomp.length(entries.len());
+
+                    for (key, value) in entries {
+                        let key = ::std::convert::AsRef::<str>::as_ref(&key);
+                        $chomp.chomp_entry(key, value)?;
+                    }
+                    Ok(())
+                }
+                Some(_) => Err($crate::Error::Undefined($path.to_owned()))
+            }
+        } else {
+            context! {
+                $self $path $part $parts $chomp
+                __iterate_dict__ $($body)*
+            }
+        }
+    };
+
     ( $self:ident $path:ident $part:ident $parts:ident $chomp:ident
       __iterate_dict__ $key:ident => @raw $val:expr, $($body:tt)*
     ) => {
@@ -262,6 +334,15 @@ macro_rules! context {
         }
     };
 
+    ( $self:ident $path:ident $part:ident $parts:ident
+      __truthy_dict__ $key:ident => @map $val:expr, $($body:tt)*
+    ) => {
+        context! {
+            $self $path $part $parts
+            __truthy_dict__ $key => @iter $val, $($body)*
+        }
+    };
+
     ( $self:ident $path:ident $part:ident $parts:ident
       __truthy_dict__ $key:ident => @iter $val:expr, $($body:tt)*
     ) => {
@@ -309,4 +390,82 @@ macro_rules! context {
     ) => {
         false
     };
+
+    // VALUE
+
+    ( $self:ident $path:ident $part:ident $parts:ident
+      __value_dict__ $key:ident => @{ $($val:tt)* }, $($body:tt)*
+    ) => {
+        if stringify!($key) == $part {
+            if let Some(part) = $parts.next() {
+                context! {
+                    $self $path part $parts
+                    __value_dict__ $($val)*
+                }
+            } else {
+                // Maps have no value.
+                None
+            }
+        } else {
+            context! {
+                $self $path $part $parts
+                __value_dict__ $($body)*
+            }
+        }
+    };
+
+    ( $self:ident $path:ident $part:ident $parts:ident
+      __value_dict__ $key:ident => @map $val:expr, $($body:tt)*
+    ) => {
+        context! {
+            $self $path $part $parts
+            __value_dict__ $key => @iter $val, $($body)*
+        }
+    };
+
+    ( $self:ident $path:ident $part:ident $parts:ident
+      __value_dict__ $key:ident => @iter $val:expr, $($body:tt)*
+    ) => {
+        if stringify!($key) == $part {
+            // Lists have no value.
+            None
+        } else {
+            context! {
+                $self $path $part $parts
+                __value_dict__ $($body)*
+            }
+        }
+    };
+
+    ( $self:ident $path:ident $part:ident $parts:ident
+      __value_dict__ $key:ident => @raw $val:expr, $($body:tt)*
+    ) => {
+        context! {
+            $self $path $part $parts
+            __value_dict__ $key => $val, $($body)*
+        }
+    };
+
+    ( $self:ident $path:ident $part:ident $parts:ident
+      __value_dict__ $key:ident => $val:expr, $($body:tt)*
+    ) => {
+        if stringify!($key) == $part {
+            // The value may be a temporary, so it can't be borrowed.
+            let value = (&$val as &dyn $crate::Context<ZZZ>)
+                .value($parts.as_path())
+                .map($crate::context::Value::into_owned);
+            value
+        } else {
+            context! {
+                $self $path $part $parts
+                __value_dict__ $($body)*
+            }
+        }
+    };
+
+    ( $self:ident $path:ident $part:ident $parts:ident
+      __value_dict__
+    ) => {
+        None
+    };
 }
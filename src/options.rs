@@ -0,0 +1,157 @@
+use error::Error;
+use escape::{Escaper, Html};
+use std::fmt;
+use std::sync::Arc;
+
+/// What to do when a template refers to a variable that isn't defined.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Undefined {
+    /// Injecting or looping over an undefined variable is an error, but
+    /// conditions treat it as false. This is the default.
+    #[default]
+    Error,
+    /// Any use of an undefined variable is an error, even in a condition.
+    Strict,
+    /// Undefined variables render as nothing, are false in conditions and
+    /// empty in loops.
+    Lenient,
+    /// Like `Lenient`, but undefined variables render as the given text,
+    /// escaped just as their values would have been.
+    Placeholder(String),
+}
+
+type Hook = Box<dyn Fn(&str) + Send + Sync>;
+type ErrorHook = Box<dyn Fn(&Error) + Send + Sync>;
+
+/// Settings that affect how a `Tenjin` renders its templates.
+///
+/// ```ignore
+/// tenjin.set_options(RenderOptions::new()
+///     .undefined(Undefined::Lenient)
+///     .on_undefined(|path| eprintln!("undefined variable '{}'", path)));
+/// ```
+pub struct RenderOptions {
+    undefined: Undefined,
+    on_undefined: Option<Hook>,
+    max_include_depth: usize,
+    escaper: Arc<dyn Escaper>,
+    reload: bool,
+    on_reload_error: Option<ErrorHook>,
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions {
+            undefined: Undefined::default(),
+            on_undefined: None,
+            max_include_depth: 64,
+            escaper: Arc::new(Html),
+            reload: false,
+            on_reload_error: None,
+        }
+    }
+}
+
+impl RenderOptions {
+    pub fn new() -> RenderOptions {
+        RenderOptions::default()
+    }
+
+    /// Sets the policy for undefined variables.
+    pub fn undefined(mut self, policy: Undefined) -> RenderOptions {
+        self.undefined = policy;
+        self
+    }
+
+    /// Sets a hook that's called with the path, as written in the template,
+    /// of every undefined variable that's used, whatever the policy is. Fallbacks, as in
+    /// `{ x or y }`, don't count as uses of `x`.
+    pub fn on_undefined<F>(mut self, hook: F) -> RenderOptions
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.on_undefined = Some(Box::new(hook));
+        self
+    }
+
+    /// Sets how deeply templates may be nested with `include` and
+    /// `extends`, which defaults to 64. Going any deeper is an error, so that
+    /// a template that includes itself can't overflow the stack.
+    pub fn max_include_depth(mut self, depth: usize) -> RenderOptions {
+        self.max_include_depth = depth;
+        self
+    }
+
+    pub(crate) fn include_depth(&self) -> usize {
+        self.max_include_depth
+    }
+
+    /// Sets how variables are escaped in templates that don't have their own
+    /// escaper, which defaults to `escape::Html`.
+    pub fn escaper<E: Escaper + 'static>(mut self, escaper: E) -> RenderOptions {
+        self.escaper = Arc::new(escaper);
+        self
+    }
+
+    pub(crate) fn default_escaper(&self) -> &dyn Escaper {
+        &*self.escaper
+    }
+
+    /// Sets whether templates read from files are recompiled when the files
+    /// change, which is handy during development. Each time a template is
+    /// looked up, its file's modification time is checked, and if it has
+    /// changed, the template is compiled again. If that fails, the previous
+    /// version is kept until the file changes again, and the error is passed
+    /// to the `on_reload_error` hook.
+    pub fn reload(mut self, reload: bool) -> RenderOptions {
+        self.reload = reload;
+        self
+    }
+
+    pub(crate) fn reloads(&self) -> bool {
+        self.reload
+    }
+
+    /// Sets a hook that's called with the error whenever a changed template
+    /// file can't be reloaded, e.g. because it doesn't compile anymore.
+    pub fn on_reload_error<F>(mut self, hook: F) -> RenderOptions
+    where
+        F: Fn(&Error) + Send + Sync + 'static,
+    {
+        self.on_reload_error = Some(Box::new(hook));
+        self
+    }
+
+    pub(crate) fn report_reload(&self, error: &Error) {
+        if let Some(ref hook) = self.on_reload_error {
+            hook(error);
+        }
+    }
+
+    /// Whether conditions have to be checked for undefined variables.
+    pub(crate) fn checks_conditions(&self) -> bool {
+        self.undefined == Undefined::Strict || self.on_undefined.is_some()
+    }
+
+    /// Reports an undefined variable, and returns the policy for it.
+    pub(crate) fn report(&self, path: &str) -> &Undefined {
+        if let Some(ref hook) = self.on_undefined {
+            hook(path);
+        }
+
+        &self.undefined
+    }
+}
+
+impl fmt::Debug for RenderOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RenderOptions")
+            .field("undefined", &self.undefined)
+            .field("on_undefined", &self.on_undefined.is_some())
+            .field("max_include_depth", &self.max_include_depth)
+            .field("escaper", &self.escaper)
+            .field("reload", &self.reload)
+            .field("on_reload_error", &self.on_reload_error.is_some())
+            .finish()
+    }
+}
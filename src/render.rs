@@ -1,85 +1,144 @@
-use compile::{Statement, Template};
-use context::Context;
-use error::{Error, Result};
-use path::Path;
-use std::borrow::Borrow;
-use std::collections::HashMap;
-use std::fs;
-use std::fs::File;
+use compile::{Expr, Op, Statement, Template};
+use context::{Context, Value};
+use error::{Error, Location, Result};
+use escape::{Escape, Escaper};
+use filter::{self, Filter};
+use load::{self, Directory, Loader};
+use options::{RenderOptions, Undefined};
+use path::{Path, PathBuf};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::path::PathBuf as FilePathBuf;
-use std::io::{Read, Write};
+use std::io::{self, Write};
+use std::str;
+use std::sync::{Arc, PoisonError, RwLock};
 
 //TODO: Documentation.
 //TODO: Benchmarks.
 
-#[derive(Debug)]
+/// A set of templates, along with the filters and options for rendering them.
+///
+/// A `Tenjin` can be shared between threads, e.g. in an `Arc`, and templates
+/// can be registered with it at any time. Each render uses the templates that
+/// were registered when it started, even if some are replaced meanwhile.
+///
+/// With `RenderOptions::reload`, that's no longer quite true: a template whose
+/// file changes during a render is reloaded as soon as it's next included, so
+/// a render can mix old and new versions of its templates.
 pub struct Tenjin {
-    templates: HashMap<String, Template>,
+    // Replaced as a whole whenever a template is added, so that renders can
+    // hold on to the one they started with.
+    templates: RwLock<Arc<Templates>>,
+    loader: Option<Box<dyn Loader>>,
+    filters: HashMap<String, Box<dyn Filter>>,
+    options: RenderOptions,
 }
 
 impl Tenjin {
+    /// Registers every HTML file under a directory, named by its path
+    /// without the extension. See `from_directory` for other kinds of files.
     pub fn new(path: &mut FilePathBuf) -> Result<Tenjin> {
-        fn recurse(
-            path: &mut FilePathBuf,
-            buf: &mut String,
-            tenjin: &mut Tenjin,
-            skip: usize,
-        ) -> Result<()> {
-            if path.is_dir() {
-                for entry in fs::read_dir(&path)?.flat_map(|x| x.ok()) {
-                    path.push(entry.file_name());
-                    recurse(path, buf, tenjin, skip)?;
-                    path.pop();
-                }
-            } else if path.extension() == Some("html".as_ref()) {
-                let mut parts = path.components();
+        Tenjin::from_directory(&Directory::new(path.clone()))
+    }
 
-                for _ in 0..skip {
-                    let _ = parts.next();
-                }
+    /// Registers every template in a directory, up front.
+    pub fn from_directory(directory: &Directory) -> Result<Tenjin> {
+        let tenjin = Tenjin::empty();
+        tenjin.register_all(directory.load_all()?);
+        Ok(tenjin)
+    }
 
-                let mut name = parts
-                    .as_path()
-                    .to_string_lossy()
-                    .into_owned();
+    pub fn empty() -> Tenjin {
+        Tenjin {
+            templates: RwLock::new(Arc::new(HashMap::new())),
+            loader: None,
+            filters: filter::builtins(),
+            options: RenderOptions::new(),
+        }
+    }
 
-                let new_len = name.len().saturating_sub(5);
-                name.truncate(new_len);
+    /// Adds a template, or replaces the one with the same name. Renders that
+    /// are already underway carry on with the previous one.
+    pub fn register<S: Into<String>>(
+        &self,
+        name: S,
+        mut template: Template
+    ) -> Option<Arc<Template>> {
+        let name = name.into();
+        template.set_name(name.as_str());
 
-                buf.clear();
-                File::open(&path)?.read_to_string(buf)?;
-                let template = Template::compile(buf)?;
-                tenjin.register(name, template);
-            }
+        self.update(|templates| templates.insert(name, Arc::new(template)))
+    }
 
-            Ok(())
-        }
+    /// Adds or replaces several templates at once, so that no render sees
+    /// some of them but not others.
+    pub fn register_all<I, S>(&self, templates: I)
+    where
+        I: IntoIterator<Item = (S, Template)>,
+        S: Into<String>,
+    {
+        let templates: Vec<_> = templates
+            .into_iter()
+            .map(|(name, mut template)| {
+                let name = name.into();
+                template.set_name(name.as_str());
+                (name, Arc::new(template))
+            })
+            .collect();
 
-        let mut tenjin = Tenjin::empty();
-        let mut buffer = String::new();
-        let skip = path.components().count();
+        self.update(|x| x.extend(templates));
+    }
 
-        recurse(path, &mut buffer, &mut tenjin, skip)?;
+    /// Finds a template that was registered or loaded before, or else asks
+    /// the loader for it. With `RenderOptions::reload`, templates whose files
+    /// have changed are compiled again first.
+    pub fn get(&self, name: &str) -> Result<Option<Arc<Template>>> {
+        match self.snapshot().get(name) {
+            Some(template) => self.refresh(name, template).map(Some),
+            None => self.load(name),
+        }
+    }
 
-        Ok(tenjin)
+    /// Sets where templates that haven't been registered are loaded from.
+    pub fn set_loader<L: Loader + 'static>(&mut self, loader: L) {
+        self.loader = Some(Box::new(loader));
     }
 
-    pub fn empty() -> Tenjin {
-        Tenjin {
-            templates: HashMap::new(),
-        }
+    /// Makes a filter available to templates, replacing any built-in filter
+    /// of the same name.
+    pub fn register_filter<S, F>(
+        &mut self,
+        name: S,
+        filter: F,
+    ) -> Option<Box<dyn Filter>>
+    where
+        S: Into<String>,
+        F: for<'a> Fn(Value<'a>, &[Value<'a>]) -> Result<Value<'a>>,
+        F: Send + Sync + 'static,
+    {
+        self.register_filter_boxed(name, Box::new(filter))
     }
 
-    pub fn register<S: Into<String>>(
+    /// Like `register_filter`, but for any implementation of `Filter`, e.g.
+    /// a struct with settings of its own.
+    pub fn register_filter_boxed<S>(
         &mut self,
         name: S,
-        template: Template
-    ) -> Option<Template> {
-        self.templates.insert(name.into(), template)
+        filter: Box<dyn Filter>,
+    ) -> Option<Box<dyn Filter>>
+    where
+        S: Into<String>,
+    {
+        self.filters.insert(name.into(), filter)
+    }
+
+    pub fn options(&self) -> &RenderOptions {
+        &self.options
     }
 
-    pub fn get(&self, name: &str) -> Option<&Template> {
-        self.templates.get(name)
+    pub fn set_options(&mut self, options: RenderOptions) {
+        self.options = options;
     }
 
     pub fn render<W: Write>(
@@ -87,71 +146,742 @@ impl Tenjin {
         template: &Template,
         context: &Context<W>,
         sink: &mut W,
+    ) -> Result<()> {
+        let templates = self.snapshot();
+        self.render_template(template, context, sink, &templates, &[], None)
+    }
+
+    /// Renders the named template, with its name attached to any error.
+    pub fn render_named<W: Write>(
+        &self,
+        name: &str,
+        context: &dyn Context<W>,
+        sink: &mut W,
+    ) -> Result<()> {
+        let templates = self.snapshot();
+        let template = self.lookup(&templates, name)?;
+
+        let includes = Includes {
+            name,
+            depth: 0,
+            outer: None,
+        };
+
+        self.render_template(&template, context, sink, &templates, &[], Some(&includes))
+            .map_err(|e| Error::InTemplate(name.into(), Box::new(e)))
+    }
+
+    /// Renders a template into a new string.
+    pub fn render_to_string(
+        &self,
+        template: &Template,
+        context: &dyn Context<Vec<u8>>,
+    ) -> Result<String> {
+        let mut sink = Vec::new();
+        self.render(template, context, &mut sink)?;
+
+        String::from_utf8(sink).map_err(|e| {
+            Error::Io(io::Error::new(io::ErrorKind::InvalidData, e))
+        })
+    }
+
+    /// Renders a template into a `fmt::Write`, like a `String` or the
+    /// `Formatter` of a `Display` impl.
+    pub fn render_fmt<F, C>(
+        &self,
+        template: &Template,
+        context: &C,
+        sink: &mut F,
+    ) -> Result<()>
+    where
+        F: fmt::Write + ?Sized,
+        C: for<'a> Context<FmtWriter<'a, F>> + ?Sized,
+    {
+        let mut sink = FmtWriter::new(sink);
+        self.render(template, &context, &mut sink)
+    }
+
+    /// Like `register`, but fails if the template would complete a cycle of
+    /// templates that include or extend each other. This is optional, since
+    /// a template may include itself on purpose, e.g. to render a tree.
+    pub fn register_checked<S: Into<String>>(
+        &self,
+        name: S,
+        template: Template,
+    ) -> Result<Option<Arc<Template>>> {
+        fn visit(
+            tenjin: &Tenjin,
+            template: &Template,
+            chain: &mut Vec<String>,
+            done: &mut HashSet<String>,
+        ) -> Result<()> {
+            for next in template.includes().into_iter().chain(template.parent()) {
+                if next == chain[0] {
+                    chain.push(next.into());
+                    return Err(Error::IncludeCycle(chain.clone()));
+                }
+
+                if chain.iter().any(|x| x == next) || done.contains(next) {
+                    continue;
+                }
+
+                if let Some(template) = tenjin.get(next)? {
+                    chain.push(next.into());
+                    visit(tenjin, &template, chain, done)?;
+                    done.insert(chain.pop().unwrap());
+                }
+            }
+
+            Ok(())
+        }
+
+        let name = name.into();
+        let mut chain = vec![name.clone()];
+
+        visit(self, &template, &mut chain, &mut HashSet::new())?;
+
+        Ok(self.register(name, template))
+    }
+
+    /// Renders a template that is extended by `children`, most derived first.
+    fn render_template<W: Write>(
+        &self,
+        template: &Template,
+        context: &dyn Context<W>,
+        sink: &mut W,
+        templates: &Templates,
+        children: &[&Template],
+        includes: Option<&Includes>,
+    ) -> Result<()> {
+        if let Some(name) = template.parent() {
+            let parent = self.lookup(templates, name)?;
+            let includes = self.enter(name, includes)?;
+            let mut chain = children.to_vec();
+            chain.push(template);
+
+            return self.render_template(
+                &parent,
+                context,
+                sink,
+                templates,
+                &chain,
+                Some(&includes),
+            );
+        }
+
+        // The most derived template decides how to escape variables.
+        let escaper = children.first().unwrap_or(&template)
+            .escaper()
+            .unwrap_or_else(|| self.options.default_escaper());
+
+        let frame = Frame {
+            templates,
+            children,
+            block: None,
+            includes,
+            escaper,
+        };
+
+        self.render_body(template, context, sink, &frame)
+    }
+
+    fn render_body<W: Write>(
+        &self,
+        template: &Template,
+        context: &dyn Context<W>,
+        sink: &mut W,
+        frame: &Frame,
+    ) -> Result<()> {
+        for (statement, span) in template.body().iter().zip(template.spans()) {
+            let res = self.render_statement(statement, context, sink, frame);
+
+            match (statement, res) {
+                (_, Ok(())) => {}
+                // Errors from nested bodies already know where they came from.
+                (&Statement::Cond { .. }, Err(e @ Error::At(..)))
+                | (&Statement::Block { .. }, Err(e @ Error::At(..)))
+                | (&Statement::Super, Err(e @ Error::At(..))) => return Err(e),
+                (_, Err(e)) => {
+                    let location = Location {
+                        template: template.name().map(String::from),
+                        line: span.line,
+                        column: span.column,
+                    };
+
+                    return Err(Error::At(location, Box::new(e)));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn render_statement<W: Write>(
+        &self,
+        statement: &Statement,
+        context: &dyn Context<W>,
+        sink: &mut W,
+        frame: &Frame,
     ) -> Result<()> {
         use self::Statement::*;
 
-        for statement in template.body() {
-            match statement {
-                &Cond { ref pred, ref then, ref otherwise } => {
-                    if context.truthy(Path::new(&pred)) {
-                        self.render(then, context, sink)?;
-                    } else {
-                        if let &Some(ref otherwise) = otherwise {
-                            self.render(otherwise, context, sink)?;
-                        } else {
-                            // No else block.
-                        }
+        match statement {
+            &Cond { ref branches, ref otherwise } => {
+                let mut branch = None;
+
+                for (pred, then) in branches {
+                    if self.test(context, pred)? {
+                        branch = Some(then);
+                        break;
                     }
                 }
-                &For { ref ident, ref path, ref body } => {
-                    context.iterate(Path::new(path), Chomp {
+
+                if let Some(then) = branch.or(otherwise.as_ref()) {
+                    self.render_body(then, context, sink, frame)?;
+                } else {
+                    // No else block.
+                }
+            }
+            &For { ref ident, ref key, ref path, ref body, ref otherwise } => {
+                let mut count = 0;
+
+                let res = context.iterate(Path::new(path), Chomp {
+                    target: Target::Render {
                         caller: self,
                         body: body,
                         context: context,
+                        frame,
                         ident: ident,
+                        key: key.as_deref(),
                         sink: sink,
-                    })?;
-                },
-                &Include { ref template, context: ref next } => {
-                    if let Some(template) = self.templates.get(template) {
-                        match next {
-                            &Some(ref next) => self.render(
-                                template,
-                                &IncludeContext {
-                                    inner: context,
-                                    path: next,
-                                },
-                                sink,
-                            ),
-                            &None => self.render(
-                                template,
-                                context,
-                                sink,
-                            ),
-                        }?;
-                    } else {
-                        return Err(Error::TemplateNotFound(template.clone()));
+                    },
+                    index: &mut count,
+                    length: None,
+                });
+
+                match res {
+                    Err(Error::Undefined(_)) if !defined(context, Path::new(path)) => {
+                        match *self.options.report(path) {
+                            Undefined::Error | Undefined::Strict => {
+                                return Err(Error::Undefined(path.clone()));
+                            }
+                            _ => {}
+                        }
                     }
-                },
-                &Inject { ref path } => {
-                    context.inject(Path::new(path), sink)?;
-                },
-                &Content { ref content } => {
-                    sink.write_all(content.as_bytes())?;
-                },
+                    res => res?,
+                }
+
+                if let (0, Some(otherwise)) = (count, otherwise.as_ref()) {
+                    self.render_body(otherwise, context, sink, frame)?;
+                }
+            },
+            &Include { template: ref name, context: ref next } => {
+                let template = self.lookup(frame.templates, name)?;
+                let includes = self.enter(name, frame.includes)?;
+
+                match next {
+                    &Some(ref next) => self.render_template(
+                        &template,
+                        &IncludeContext {
+                            inner: context,
+                            path: next,
+                        },
+                        sink,
+                        frame.templates,
+                        &[],
+                        Some(&includes),
+                    ),
+                    &None => self.render_template(
+                        &template,
+                        context,
+                        sink,
+                        frame.templates,
+                        &[],
+                        Some(&includes),
+                    ),
+                }?;
+            },
+            &Inject { ref path, ref fallbacks, ref filters, position }
+                if fallbacks.is_empty() && filters.is_empty() => {
+                let escape = Escape::new(frame.escaper, position);
+
+                match context.inject(Path::new(path), escape, sink) {
+                    Err(Error::Undefined(_)) => self.undefined(path, escape, sink)?,
+                    res => res?,
+                }
+            },
+            &Inject { ref path, ref fallbacks, ref filters, position } => {
+                let escape = Escape::new(frame.escaper, position);
+
+                // The first truthy alternative wins, or else the last.
+                let choice = if context.truthy(Path::new(path)) {
+                    None
+                } else {
+                    fallbacks.iter()
+                        .find(|x| test(context, x))
+                        .or(fallbacks.last())
+                };
+
+                let (path, literal) = match choice {
+                    Some(Expr::Path(x)) => (x, None),
+                    Some(x) => (path, value(context, x)),
+                    None => (path, None),
+                };
+
+                if literal.is_none() && filters.is_empty() {
+                    match context.inject(Path::new(path), escape, sink) {
+                        Err(Error::Undefined(_)) => self.undefined(path, escape, sink)?,
+                        res => res?,
+                    }
+
+                    return Ok(());
+                }
+
+                let mut value = match literal {
+                    Some(value) => value,
+                    None => collect(context, Path::new(path)),
+                };
+
+                for (name, args) in filters {
+                    let filter = match self.filters.get(name) {
+                        Some(filter) => filter,
+                        None => return Err(Error::FilterNotFound(name.clone())),
+                    };
+
+                    value = filter.apply(value, args)?;
+                }
+
+                if let Value::Null = value {
+                    self.undefined(path, escape, sink)?;
+                } else {
+                    value.inject(Path::new(""), escape, sink)?;
+                }
+            },
+            &Content { ref content } => {
+                sink.write_all(content.as_bytes())?;
+            },
+            &Extends { .. } => {
+                // Handled by `render_template`.
+            },
+            &Block { ref name, ref body } => {
+                self.render_block(name, 0, Some(body), context, sink, frame)?;
+            },
+            &Super => {
+                if let Some((name, next, base)) = frame.block {
+                    self.render_block(name, next, base, context, sink, frame)?;
+                }
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Renders the most derived definition of a block, skipping the first
+    /// `skip` children, and falling back to the parent's definition.
+    fn render_block<W: Write>(
+        &self,
+        name: &str,
+        skip: usize,
+        base: Option<&Template>,
+        context: &dyn Context<W>,
+        sink: &mut W,
+        frame: &Frame,
+    ) -> Result<()> {
+        let children = frame.children;
+
+        for (i, child) in children.iter().enumerate().skip(skip) {
+            if let Some(body) = child.block(name) {
+                let frame = Frame {
+                    templates: frame.templates,
+                    children,
+                    block: Some((name, i + 1, base)),
+                    includes: frame.includes,
+                    escaper: frame.escaper,
+                };
+
+                return self.render_body(body, context, sink, &frame);
+            }
+        }
+
+        if let Some(body) = base {
+            let frame = Frame {
+                templates: frame.templates,
+                children,
+                block: Some((name, children.len(), None)),
+                includes: frame.includes,
+                escaper: frame.escaper,
+            };
+
+            self.render_body(body, context, sink, &frame)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Tenjin {
+    /// The templates that are registered right now.
+    fn snapshot(&self) -> Arc<Templates> {
+        self.templates.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    /// Changes the registered templates, copying them first if a render is
+    /// still using them.
+    fn update<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce(&mut Templates) -> T,
+    {
+        let mut templates = self.templates.write().unwrap_or_else(PoisonError::into_inner);
+        f(Arc::make_mut(&mut templates))
+    }
+
+    /// Finds a template for a render that started with the given templates,
+    /// loading it if it wasn't there. Templates registered since the render
+    /// started aren't used, but with `RenderOptions::reload`, files that have
+    /// changed since are.
+    fn lookup(&self, templates: &Templates, name: &str) -> Result<Arc<Template>> {
+        let template = match templates.get(name) {
+            Some(template) => Some(self.refresh(name, template)?),
+            None => self.load(name)?,
+        };
+
+        template.ok_or_else(|| Error::TemplateNotFound(name.into()))
+    }
+
+    /// Compiles a template again if reloading is on and its file has changed,
+    /// or else returns it as it is.
+    fn refresh(&self, name: &str, template: &Arc<Template>) -> Result<Arc<Template>> {
+        let file = match template.file() {
+            Some(file) if self.options.reloads() => file,
+            _ => return Ok(template.clone()),
+        };
+
+        let modified = load::modified(file);
+
+        if modified == template.modified() {
+            return Ok(template.clone());
+        }
+
+        let mut template = match load::read(file) {
+            Ok(template) => template,
+            Err(e) => {
+                // The previous version is used until the file changes again,
+                // rather than failing every time until then.
+                self.options.report_reload(&e);
+
+                let mut previous = (**template).clone();
+                previous.set_file(file.to_owned(), modified);
+                previous
+            }
+        };
+
+        template.set_name(name);
+
+        let template = Arc::new(template);
+        self.update(|templates| templates.insert(name.into(), template.clone()));
+        Ok(template)
+    }
+
+    /// Asks the loader for a template, caching it unless it was registered in
+    /// the meantime. The loaded template is returned either way, so that a
+    /// render never picks up one that was registered after it started.
+    fn load(&self, name: &str) -> Result<Option<Arc<Template>>> {
+        let mut template = match self.loader {
+            Some(ref loader) => match loader.load(name)? {
+                Some(template) => template,
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+
+        template.set_name(name);
+
+        let template = Arc::new(template);
+        self.update(|templates| {
+            templates.entry(name.into()).or_insert_with(|| template.clone());
+        });
+
+        Ok(Some(template))
+    }
+
+    /// Steps into an included or extended template, checking that it isn't
+    /// nested too deeply.
+    fn enter<'a>(
+        &self,
+        name: &'a str,
+        outer: Option<&'a Includes<'a>>,
+    ) -> Result<Includes<'a>> {
+        let includes = Includes {
+            name,
+            depth: outer.map_or(1, |x| x.depth + 1),
+            outer,
+        };
+
+        if includes.depth > self.options.include_depth() {
+            return Err(Error::IncludeDepth(includes.names()));
+        }
+
+        Ok(includes)
+    }
+
+    /// Evaluates a predicate, reporting the undefined variables in it.
+    fn test<W: Write>(&self, context: &dyn Context<W>, expr: &Expr) -> Result<bool> {
+        if self.options.checks_conditions() {
+            self.check(context, expr)?;
+        }
+
+        Ok(test(context, expr))
+    }
+
+    fn check<W: Write>(&self, context: &dyn Context<W>, expr: &Expr) -> Result<()> {
+        match *expr {
+            Expr::Path(ref path) => {
+                if !defined(context, Path::new(path)) {
+                    if let Undefined::Strict = *self.options.report(path) {
+                        return Err(Error::Undefined(path.clone()));
+                    }
+                }
+            }
+            Expr::Literal(_) => {}
+            Expr::Not(ref x) => self.check(context, x)?,
+            Expr::And(ref x, ref y)
+            | Expr::Or(ref x, ref y)
+            | Expr::Compare(_, ref x, ref y) => {
+                self.check(context, x)?;
+                self.check(context, y)?;
             }
         }
 
         Ok(())
     }
+
+    /// Handles an undefined variable that was about to be written out, with
+    /// any placeholder escaped as the variable would have been.
+    fn undefined<W: Write>(&self, path: &PathBuf, escape: Escape, sink: &mut W) -> Result<()> {
+        match *self.options.report(path) {
+            Undefined::Error | Undefined::Strict => Err(Error::Undefined(path.clone())),
+            Undefined::Lenient => Ok(()),
+            Undefined::Placeholder(ref text) => {
+                escape.write(text, sink)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Whether the path refers to anything at all, even something falsey.
+fn defined<W: Write>(context: &dyn Context<W>, path: Path) -> bool {
+    if context.truthy(path) || context.value(path).is_some() {
+        return true;
+    }
+
+    let mut items = Vec::new();
+    let mut count = 0;
+
+    let chomp = Chomp {
+        target: Target::Collect(&mut items),
+        index: &mut count,
+        length: None,
+    };
+
+    !matches!(context.iterate(path, chomp), Err(Error::Undefined(_)))
+}
+
+/// Evaluates a predicate against the context.
+fn test<W>(context: &dyn Context<W>, expr: &Expr) -> bool {
+    match *expr {
+        Expr::Path(ref path) => context.truthy(Path::new(path)),
+        Expr::Literal(ref value) => value.truthy(),
+        Expr::Not(ref x) => !test(context, x),
+        Expr::And(ref x, ref y) => test(context, x) && test(context, y),
+        Expr::Or(ref x, ref y) => test(context, x) || test(context, y),
+        Expr::Compare(op, ref x, ref y) => {
+            let order = match (value(context, x), value(context, y)) {
+                (Some(x), Some(y)) => x.compare(&y),
+                _ => None,
+            };
+
+            match op {
+                Op::Eq => order == Some(Ordering::Equal),
+                Op::Ne => order != Some(Ordering::Equal),
+                Op::Lt => order == Some(Ordering::Less),
+                Op::Le => order.is_some_and(|x| x != Ordering::Greater),
+                Op::Gt => order == Some(Ordering::Greater),
+                Op::Ge => order.is_some_and(|x| x != Ordering::Less),
+            }
+        }
+    }
+}
+
+/// Extracts the value at the path, including lists, for use by filters.
+fn collect<'a, W: Write>(context: &'a dyn Context<W>, path: Path) -> Value<'a> {
+    if let Some(value) = context.value(path) {
+        return value;
+    }
+
+    let mut items = Vec::new();
+    let mut count = 0;
+
+    let chomp = Chomp {
+        target: Target::Collect(&mut items),
+        index: &mut count,
+        length: None,
+    };
+
+    match context.iterate(path, chomp) {
+        Ok(()) => Value::List(items),
+        Err(_) => Value::Null,
+    }
+}
+
+/// Evaluates an operand of a comparison.
+fn value<'a, W>(context: &'a dyn Context<W>, expr: &'a Expr) -> Option<Value<'a>> {
+    match *expr {
+        Expr::Path(ref path) => context.value(Path::new(path)),
+        Expr::Literal(ref value) => Some(value.borrowed()),
+        _ => Some(Value::Bool(test(context, expr))),
+    }
+}
+
+/// Adapts a `fmt::Write` for use as a sink.
+pub struct FmtWriter<'a, F: 'a + ?Sized> {
+    inner: &'a mut F,
+    // The start of a character that was split between writes.
+    partial: [u8; 4],
+    len: usize,
+}
+
+impl<'a, F: fmt::Write + ?Sized> FmtWriter<'a, F> {
+    pub fn new(inner: &'a mut F) -> FmtWriter<'a, F> {
+        FmtWriter {
+            inner,
+            partial: [0; 4],
+            len: 0,
+        }
+    }
+
+    fn write_str(&mut self, s: &str) -> io::Result<()> {
+        self.inner.write_str(s).map_err(|_| io::Error::other("formatter error"))
+    }
+}
+
+impl<'a, F: fmt::Write + ?Sized> Write for FmtWriter<'a, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+
+        // Finish the split character first.
+        while self.len > 0 && !rest.is_empty() {
+            self.partial[self.len] = rest[0];
+            self.len += 1;
+            rest = &rest[1..];
+
+            if let Ok(s) = str::from_utf8(&self.partial[..self.len]) {
+                let c = s.chars().next().unwrap();
+                self.len = 0;
+                self.write_str(c.encode_utf8(&mut [0; 4]))?;
+            } else if self.len == 4 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid UTF-8"));
+            }
+        }
+
+        match str::from_utf8(rest) {
+            Ok(s) => self.write_str(s)?,
+            Err(e) => {
+                let (valid, split) = rest.split_at(e.valid_up_to());
+                self.write_str(str::from_utf8(valid).unwrap())?;
+
+                if e.error_len().is_some() {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+
+                self.partial[..split.len()].copy_from_slice(split);
+                self.len = split.len();
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Tenjin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut filters: Vec<_> = self.filters.keys().collect();
+        filters.sort();
+
+        f.debug_struct("Tenjin")
+            .field("templates", &self.templates)
+            .field("loader", &self.loader)
+            .field("filters", &filters)
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+type Templates = HashMap<String, Arc<Template>>;
+
+/// The inheritance state of the template being rendered.
+struct Frame<'a> {
+    // The templates that were registered when rendering started.
+    templates: &'a Templates,
+    // The templates extending it, most derived first.
+    children: &'a [&'a Template],
+    // The block being rendered, as its name, the index of the next child to
+    // search for a `super` definition and the parent's definition.
+    block: Option<(&'a str, usize, Option<&'a Template>)>,
+    // The templates that were included or extended to get here.
+    includes: Option<&'a Includes<'a>>,
+    // The escaper for variables.
+    escaper: &'a dyn Escaper,
+}
+
+/// A template that was included or extended, and the ones that were included
+/// or extended to get to it.
+struct Includes<'a> {
+    name: &'a str,
+    depth: usize,
+    outer: Option<&'a Includes<'a>>,
+}
+
+impl<'a> Includes<'a> {
+    /// The names of the templates, outermost first.
+    fn names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut next = Some(self);
+
+        while let Some(includes) = next {
+            names.push(includes.name.to_owned());
+            next = includes.outer;
+        }
+
+        names.reverse();
+        names
+    }
 }
 
 pub struct Chomp<'a, W: 'a> {
-    caller: &'a Tenjin,
-    body: &'a Template,
-    context: &'a Context<W>,
-    ident: &'a str,
-    sink: &'a mut W,
+    target: Target<'a, W>,
+    index: &'a mut usize,
+    length: Option<usize>,
+}
+
+/// What to do with each item.
+enum Target<'a, W: 'a> {
+    /// Render the body of a `for` block.
+    Render {
+        caller: &'a Tenjin,
+        body: &'a Template,
+        context: &'a Context<W>,
+        frame: &'a Frame<'a>,
+        ident: &'a str,
+        key: Option<&'a str>,
+        sink: &'a mut W,
+    },
+    /// Collect the items' values, for filters.
+    Collect(&'a mut Vec<Value<'static>>),
 }
 
 struct IncludeContext<'a, W: 'a> {
@@ -163,19 +893,76 @@ struct ForContext<'a, W: 'a> {
     back: &'a Context<W>,
     front: &'a Context<W>,
     name: &'a str,
+    key: Option<(&'a str, &'a dyn Context<W>)>,
+    meta: &'a Loop,
+}
+
+/// The `loop` variable available inside `for` blocks.
+struct Loop {
+    index: usize,
+    index0: usize,
+    first: bool,
+    last: Option<bool>,
+    length: Option<usize>,
 }
 
 impl<'a, W: Write> Chomp<'a, W> {
+    /// Tells the loop how many items there will be, if known beforehand,
+    /// which makes `loop.last` and `loop.length` available.
+    pub fn length(&mut self, length: usize) {
+        self.length = Some(length);
+    }
+
     pub fn chomp<C: Context<W>>(&mut self, item: C) -> Result<()> {
-        self.caller.render(
-            self.body,
-            &ForContext {
-                back: self.context,
-                front: item.borrow(),
-                name: self.ident,
-            },
-            self.sink,
-        )
+        // Lists are keyed by index.
+        let index = *self.index;
+        self.chomp_with(&index, &item)
+    }
+
+    /// Like `chomp`, but for an entry of a map.
+    pub fn chomp_entry<C: Context<W>>(&mut self, key: &str, item: C) -> Result<()> {
+        self.chomp_with(&key, &item)
+    }
+
+    fn chomp_with(&mut self, key: &dyn Context<W>, item: &dyn Context<W>) -> Result<()> {
+        let index = *self.index;
+        *self.index += 1;
+
+        let meta = Loop {
+            index: index + 1,
+            index0: index,
+            first: index == 0,
+            last: self.length.map(|n| index + 1 == n),
+            length: self.length,
+        };
+
+        match self.target {
+            Target::Render {
+                caller,
+                body,
+                context,
+                frame,
+                ident,
+                key: name,
+                ref mut sink,
+            } => caller.render_body(
+                body,
+                &ForContext {
+                    back: context,
+                    front: item,
+                    name: ident,
+                    key: name.map(|name| (name, key)),
+                    meta: &meta,
+                },
+                *sink,
+                frame,
+            ),
+            Target::Collect(ref mut items) => {
+                let value = item.value(Path::new(""));
+                items.push(value.map_or(Value::Null, Value::into_owned));
+                Ok(())
+            }
+        }
     }
 }
 
@@ -188,10 +975,11 @@ impl<'a, W> Context<W> for IncludeContext<'a, W> {
     fn inject(
         &self,
         path: Path,
+        escape: Escape,
         sink: &mut W
     ) -> Result<()> {
         let path = path.prepend(self.path);
-        self.inner.inject(path, sink)
+        self.inner.inject(path, escape, sink)
     }
 
     fn iterate(
@@ -202,13 +990,33 @@ impl<'a, W> Context<W> for IncludeContext<'a, W> {
         let path = path.prepend(self.path);
         self.inner.iterate(path, cb)
     }
+
+    fn value(&self, path: Path) -> Option<Value<'_>> {
+        let path = path.prepend(self.path);
+        self.inner.value(path)
+    }
 }
 
-impl<'a, W> Context<W> for ForContext<'a, W> {
+impl<'a, W> ForContext<'a, W> {
+    /// Finds the loop variable with the given name.
+    fn var(&self, name: Option<&str>) -> Option<&'a dyn Context<W>> {
+        match self.key {
+            Some((key, value)) if Some(key) == name => Some(value),
+            _ if Some(self.name) == name => Some(self.front),
+            _ => None,
+        }
+    }
+}
+
+impl<'a, W: Write> Context<W> for ForContext<'a, W> {
     fn truthy(&self, path: Path) -> bool {
         let mut parts = path.parts();
-        if parts.next() == Some(self.name.as_ref()) {
-            self.front.truthy(parts.as_path())
+        let first = parts.next();
+
+        if let Some(var) = self.var(first) {
+            var.truthy(parts.as_path())
+        } else if first == Some("loop") {
+            <Loop as Context<W>>::truthy(self.meta, parts.as_path())
         } else {
             self.back.truthy(path)
         }
@@ -217,13 +1025,19 @@ impl<'a, W> Context<W> for ForContext<'a, W> {
     fn inject(
         &self,
         path: Path,
+        escape: Escape,
         sink: &mut W
     ) -> Result<()> {
         let mut parts = path.parts();
-        if parts.next() == Some(self.name.as_ref()) {
-            self.front.inject(parts.as_path(), sink)
+        let first = parts.next();
+
+        if let Some(var) = self.var(first) {
+            var.inject(parts.as_path(), escape, sink)
+        } else if first == Some("loop") {
+            let res = self.meta.inject(parts.as_path(), escape, sink);
+            rebase(res, path)
         } else {
-            self.back.inject(path, sink)
+            self.back.inject(path, escape, sink)
         }
     }
 
@@ -233,10 +1047,417 @@ impl<'a, W> Context<W> for ForContext<'a, W> {
         cb: Chomp<W>
     ) -> Result<()> {
         let mut parts = path.parts();
-        if parts.next() == Some(self.name.as_ref()) {
-            self.front.iterate(parts.as_path(), cb)
+        let first = parts.next();
+
+        if let Some(var) = self.var(first) {
+            var.iterate(parts.as_path(), cb)
+        } else if first == Some("loop") {
+            let res = self.meta.iterate(parts.as_path(), cb);
+            rebase(res, path)
         } else {
             self.back.iterate(path, cb)
         }
     }
+
+    fn value(&self, path: Path) -> Option<Value<'_>> {
+        let mut parts = path.parts();
+        let first = parts.next();
+
+        if let Some(var) = self.var(first) {
+            var.value(parts.as_path())
+        } else if first == Some("loop") {
+            <Loop as Context<W>>::value(self.meta, parts.as_path())
+        } else {
+            self.back.value(path)
+        }
+    }
+}
+
+/// Reports errors from the `loop` variable with their full path.
+fn rebase(res: Result<()>, path: Path) -> Result<()> {
+    res.map_err(|e| match e {
+        Error::Undefined(_) => Error::Undefined(path.to_owned()),
+        Error::NotInjectable(_) => Error::NotInjectable(path.to_owned()),
+        Error::NotIterable(_) => Error::NotIterable(path.to_owned()),
+        e => e,
+    })
+}
+
+impl Loop {
+    fn field<W: Write>(&self, name: &str) -> Option<&dyn Context<W>> {
+        Some(match name {
+            "index" => &self.index,
+            "index0" => &self.index0,
+            "first" => &self.first,
+            "last" => &self.last,
+            "length" => &self.length,
+            _ => return None,
+        })
+    }
+}
+
+impl<W: Write> Context<W> for Loop {
+    fn truthy(&self, path: Path) -> bool {
+        let mut parts = path.parts();
+        match parts.next() {
+            Some(x) => self.field::<W>(x).is_some_and(|x| x.truthy(parts.as_path())),
+            // All maps are truthy.
+            None => true,
+        }
+    }
+
+    fn inject(&self, path: Path, escape: Escape, sink: &mut W) -> Result<()> {
+        let mut parts = path.parts();
+        match parts.next().map(|x| self.field(x)) {
+            Some(Some(x)) => x.inject(parts.as_path(), escape, sink),
+            Some(None) => Err(Error::Undefined(path.to_owned())),
+            None => Err(Error::NotInjectable(path.to_owned())),
+        }
+    }
+
+    fn iterate(&self, path: Path, chomp: Chomp<W>) -> Result<()> {
+        let mut parts = path.parts();
+        match parts.next().map(|x| self.field(x)) {
+            Some(Some(x)) => x.iterate(parts.as_path(), chomp),
+            Some(None) => Err(Error::Undefined(path.to_owned())),
+            None => Err(Error::NotIterable(path.to_owned())),
+        }
+    }
+
+    fn value(&self, path: Path) -> Option<Value<'_>> {
+        let mut parts = path.parts();
+
+        let value = match parts.next()? {
+            "index" => Value::from(self.index),
+            "index0" => Value::from(self.index0),
+            "first" => Value::Bool(self.first),
+            "last" => Value::Bool(self.last?),
+            "length" => Value::from(self.length?),
+            _ => return None,
+        };
+
+        match parts.next() {
+            Some(_) => None,
+            None => Some(value),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "serde_json"))]
+mod tests {
+    use super::*;
+    use serde_json::Value as Json;
+    use serde_json::json;
+
+    /// A `Tenjin` with the given templates registered.
+    fn tenjin(templates: &[(&str, &str)]) -> Tenjin {
+        let tenjin = Tenjin::empty();
+
+        for &(name, src) in templates {
+            tenjin.register(name, Template::compile(src).unwrap());
+        }
+
+        tenjin
+    }
+
+    /// Renders the named template to a string.
+    fn render(tenjin: &Tenjin, name: &str, data: &Json) -> Result<String> {
+        let mut sink = Vec::new();
+        tenjin.render_named(name, data, &mut sink)?;
+        Ok(String::from_utf8(sink).unwrap())
+    }
+
+    #[test]
+    fn blocks_replace_and_call_their_parents() {
+        let tenjin = tenjin(&[
+            ("base", "<{ block a }A{ end }|{ block b }B{ end }>"),
+            ("mid", "{ extends base }{ block a }M{ super }{ end }"),
+            ("leaf", "{ extends mid }{ block a }L{ super }{ end }{ block b }b{ end }"),
+        ]);
+
+        assert_eq!(render(&tenjin, "base", &Json::Null).unwrap(), "<A|B>");
+        assert_eq!(render(&tenjin, "mid", &Json::Null).unwrap(), "<MA|B>");
+        assert_eq!(render(&tenjin, "leaf", &Json::Null).unwrap(), "<LMA|b>");
+    }
+
+    #[test]
+    fn missing_parents_are_errors() {
+        let tenjin = tenjin(&[("leaf", "{ extends nope }")]);
+        let e = render(&tenjin, "leaf", &Json::Null).unwrap_err();
+
+        match *e.root() {
+            Error::TemplateNotFound(ref name) => assert_eq!(name, "nope"),
+            ref e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn elif_chains_pick_the_first_truthy_branch() {
+        let tenjin = tenjin(&[
+            ("elif", "{ if a }A{ elif b }B{ elif c }C{ else }D{ end }"),
+            ("else if", "{ if a }A{ else if b }B{ else }D{ end }"),
+        ]);
+
+        let cases = [
+            (json!({"a": true, "b": true}), "A", "A"),
+            (json!({"b": true, "c": true}), "B", "B"),
+            (json!({"c": true}), "C", "D"),
+            (json!({}), "D", "D"),
+        ];
+
+        for &(ref data, elif, else_if) in &cases {
+            assert_eq!(render(&tenjin, "elif", data).unwrap(), elif);
+            assert_eq!(render(&tenjin, "else if", data).unwrap(), else_if);
+        }
+    }
+
+    #[test]
+    fn loops_describe_the_current_item() {
+        let tenjin = tenjin(&[(
+            "loop",
+            "{ for x in xs }{ loop.index }/{ loop.index0 }/{ loop.length }\
+             { if loop.first }F{ end }{ if loop.last }L{ end } { end }",
+        )]);
+
+        let data = json!({"xs": ["a", "b", "c"]});
+        assert_eq!(render(&tenjin, "loop", &data).unwrap(), "1/0/3F 2/1/3 3/2/3L ");
+    }
+
+    #[test]
+    fn for_else_runs_when_there_are_no_items() {
+        let tenjin = tenjin(&[("list", "{ for x in xs }{ x }{ else }none{ end }")]);
+
+        assert_eq!(render(&tenjin, "list", &json!({"xs": [1, 2]})).unwrap(), "12");
+        assert_eq!(render(&tenjin, "list", &json!({"xs": []})).unwrap(), "none");
+    }
+
+    #[test]
+    fn filters_are_applied_in_turn() {
+        let mut tenjin = tenjin(&[
+            ("builtins", "{ name | trim | upper }|{ name | truncate 4 \"~\" }|{ xs | join \", \" }"),
+            ("custom", "{ name | shout }"),
+            ("missing", "{ name | nope }"),
+        ]);
+
+        tenjin.register_filter("shout", |value: Value, _: &[Value]| match value {
+            Value::Str(s) => Ok(Value::Str(format!("{}!", s).into())),
+            value => Ok(value),
+        });
+
+        let data = json!({"name": " tenjin ", "xs": [1, 2]});
+        assert_eq!(render(&tenjin, "builtins", &data).unwrap(), "TENJIN| ten~|1, 2");
+        assert_eq!(render(&tenjin, "custom", &data).unwrap(), " tenjin !");
+
+        match *render(&tenjin, "missing", &data).unwrap_err().root() {
+            Error::FilterNotFound(ref name) => assert_eq!(name, "nope"),
+            ref e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn fallbacks_are_used_in_turn() {
+        let tenjin = tenjin(&[
+            ("or", "{ nick or name or \"anonymous\" }"),
+            ("?", "{ nick ? \"anonymous\" }"),
+            ("last", "{ nick or empty }"),
+        ]);
+
+        assert_eq!(render(&tenjin, "or", &json!({"nick": "q", "name": "Ram"})).unwrap(), "q");
+        assert_eq!(render(&tenjin, "or", &json!({"nick": "", "name": "Ram"})).unwrap(), "Ram");
+        assert_eq!(render(&tenjin, "or", &json!({})).unwrap(), "anonymous");
+        assert_eq!(render(&tenjin, "?", &json!({})).unwrap(), "anonymous");
+        assert_eq!(render(&tenjin, "last", &json!({"empty": ""})).unwrap(), "");
+    }
+
+    #[test]
+    fn undefined_variables_follow_the_policy() {
+        let mut tenjin = tenjin(&[
+            ("inject", "<p>{ x }</p>"),
+            ("if", "{ if x }yes{ else }no{ end }"),
+            ("for", "[{ for i in x }{ i }{ end }]"),
+            ("url", "<a href=\"{ x }\">"),
+            ("script", "<script>var a = { x };</script>"),
+        ]);
+
+        let undefined = |tenjin: &Tenjin, name| match render(tenjin, name, &json!({})) {
+            Err(ref e) => matches!(*e.root(), Error::Undefined(_)),
+            Ok(_) => false,
+        };
+
+        assert!(undefined(&tenjin, "inject"));
+        assert_eq!(render(&tenjin, "if", &json!({})).unwrap(), "no");
+
+        tenjin.set_options(RenderOptions::new().undefined(Undefined::Strict));
+        assert!(undefined(&tenjin, "inject"));
+        assert!(undefined(&tenjin, "if"));
+
+        tenjin.set_options(RenderOptions::new().undefined(Undefined::Lenient));
+        assert_eq!(render(&tenjin, "inject", &json!({})).unwrap(), "<p></p>");
+        assert_eq!(render(&tenjin, "for", &json!({})).unwrap(), "[]");
+
+        let placeholder = Undefined::Placeholder("javascript:'<?>'".into());
+        tenjin.set_options(RenderOptions::new().undefined(placeholder));
+        let render = |name| render(&tenjin, name, &json!({})).unwrap();
+
+        assert_eq!(render("inject"), "<p>javascript:&#x27;&lt;?&gt;&#x27;</p>");
+        assert_eq!(render("url"), "<a href=\"#unsafe\">");
+        assert_eq!(
+            render("script"),
+            "<script>var a = \"javascript:\\u0027\\u003C?\\u003E\\u0027\";</script>"
+        );
+    }
+
+    #[test]
+    fn undefined_variables_are_reported() {
+        use std::sync::Mutex;
+
+        let mut tenjin = tenjin(&[("page", "{ if a.b }{ end }{ c }")]);
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let hook = seen.clone();
+
+        tenjin.set_options(RenderOptions::new()
+            .undefined(Undefined::Lenient)
+            .on_undefined(move |path| hook.lock().unwrap().push(path.to_owned())));
+
+        render(&tenjin, "page", &json!({})).unwrap();
+        assert_eq!(*seen.lock().unwrap(), vec!["a.b".to_owned(), "c".to_owned()]);
+    }
+
+    #[test]
+    fn includes_are_limited_in_depth() {
+        let mut tenjin = tenjin(&[("self", "x{ include self }"), ("tree", "{ include leaf }")]);
+        tenjin.register("leaf", Template::compile("leaf").unwrap());
+
+        match *render(&tenjin, "self", &Json::Null).unwrap_err().root() {
+            // The template itself, 64 includes, and the one too many.
+            Error::IncludeDepth(ref chain) => assert_eq!(chain.len(), 66),
+            ref e => panic!("unexpected error: {}", e),
+        }
+
+        tenjin.set_options(RenderOptions::new().max_include_depth(1));
+        assert_eq!(render(&tenjin, "tree", &Json::Null).unwrap(), "leaf");
+
+        tenjin.set_options(RenderOptions::new().max_include_depth(0));
+        assert!(render(&tenjin, "tree", &Json::Null).is_err());
+    }
+
+    #[test]
+    fn checked_registration_rejects_cycles() {
+        let tenjin = tenjin(&[("a", "{ include b }"), ("c", "{ extends a }")]);
+
+        match tenjin.register_checked("b", Template::compile("{ include c }").unwrap()) {
+            Err(Error::IncludeCycle(ref chain)) => assert_eq!(chain, &["b", "c", "a", "b"]),
+            res => panic!("unexpected result: {:?}", res.map(|_| ())),
+        }
+
+        let e = tenjin.register_checked("self", Template::compile("{ include self }").unwrap());
+        assert!(matches!(e, Err(Error::IncludeCycle(_))));
+
+        assert!(tenjin.register_checked("b", Template::compile("b").unwrap()).is_ok());
+        assert!(tenjin.get("self").unwrap().is_none());
+    }
+
+    #[test]
+    fn failed_reloads_keep_the_previous_version() {
+        use std::fs::{self, File};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::time::{Duration, UNIX_EPOCH};
+
+        let dir = std::env::temp_dir().join(format!("tenjin-reload-{}", std::process::id()));
+        let path = dir.join("page.html");
+
+        // With made-up modification times, so that every write is noticed.
+        let write = |source: &str, time: u64| {
+            fs::write(&path, source).unwrap();
+            let file = File::options().write(true).open(&path).unwrap();
+            file.set_modified(UNIX_EPOCH + Duration::from_secs(time)).unwrap();
+        };
+
+        fs::create_dir_all(&dir).unwrap();
+        write("v1 { x }", 1000);
+
+        let mut tenjin = Tenjin::from_directory(&Directory::new(dir.clone())).unwrap();
+        let errors = Arc::new(AtomicUsize::new(0));
+        let hook = errors.clone();
+
+        tenjin.set_options(RenderOptions::new()
+            .reload(true)
+            .on_reload_error(move |_| { hook.fetch_add(1, Ordering::SeqCst); }));
+
+        let data = json!({"x": 1});
+        assert_eq!(render(&tenjin, "page", &data).unwrap(), "v1 1");
+
+        write("v2 { x ", 2000);
+        assert_eq!(render(&tenjin, "page", &data).unwrap(), "v1 1");
+        assert_eq!(render(&tenjin, "page", &data).unwrap(), "v1 1");
+        assert_eq!(errors.load(Ordering::SeqCst), 1);
+
+        write("v3 { x }", 3000);
+        assert_eq!(render(&tenjin, "page", &data).unwrap(), "v3 1");
+        assert_eq!(errors.load(Ordering::SeqCst), 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Registers templates when `{ swap }` is written out, i.e. partway
+    /// through a render.
+    struct Swap<'a>(&'a Tenjin, &'a [(&'a str, &'a str)]);
+
+    impl<'a, W: Write> Context<W> for Swap<'a> {
+        fn truthy(&self, _: Path) -> bool {
+            false
+        }
+
+        fn inject(&self, _: Path, _: Escape, _: &mut W) -> Result<()> {
+            for &(name, src) in self.1 {
+                self.0.register(name, Template::compile(src).unwrap());
+            }
+
+            Ok(())
+        }
+
+        fn iterate(&self, _: Path, _: Chomp<W>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn renders_keep_the_templates_they_started_with() {
+        let tenjin = tenjin(&[("page", "{ swap }{ include part }"), ("part", "old")]);
+        let swap = Swap(&tenjin, &[("part", "new")]);
+
+        let mut sink = Vec::new();
+        tenjin.render_named("page", &swap, &mut sink).unwrap();
+        assert_eq!(sink, b"old");
+
+        let mut sink = Vec::new();
+        tenjin.render_named("page", &Swap(&tenjin, &[]), &mut sink).unwrap();
+        assert_eq!(sink, b"new");
+    }
+
+    #[test]
+    fn loads_ignore_templates_registered_meanwhile() {
+        let mut tenjin = tenjin(&[("page", "{ swap }{ include part }{ include gone }")]);
+
+        let mut sources = HashMap::new();
+        sources.insert("part", "loaded");
+        tenjin.set_loader(sources);
+
+        // Registered after the render started, so neither is used.
+        let swap = Swap(&tenjin, &[("part", "registered"), ("gone", "registered")]);
+
+        let mut sink = Vec::new();
+        let e = tenjin.render_named("page", &swap, &mut sink).unwrap_err();
+        assert_eq!(sink, b"loaded");
+
+        match *e.root() {
+            Error::TemplateNotFound(ref name) => assert_eq!(name, "gone"),
+            ref e => panic!("unexpected error: {}", e),
+        }
+
+        // A registered template wins over a loaded one.
+        let mut sink = Vec::new();
+        tenjin.render_named("page", &Swap(&tenjin, &[]), &mut sink).unwrap();
+        assert_eq!(sink, b"registeredregistered");
+    }
 }